@@ -1,19 +1,53 @@
-use crate::history_item::HistoryItem;
-use anyhow::Result;
+use crate::fuzzy::fuzzy_match_with_case;
+use crate::history_item::{HistoryItem, UniqueKey};
+use crate::import::split_command_line;
+use crate::query::Query;
+use crate::typo::{max_typos, min_shared_trigrams, quality, similarity, trigrams, typo_match};
+use anyhow::{bail, Result};
 use chrono::prelude::{DateTime, TimeZone};
 use chrono::Utc;
 use itertools::Itertools;
 use log::debug;
 use log::info;
-use rusqlite::{params, Connection, Row, Transaction};
-use std::path::Path;
-use std::time::{Duration, UNIX_EPOCH};
+use regex::Regex;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::hooks::Action;
+use rusqlite::types::Value;
+use rusqlite::types::ValueRef;
+use rusqlite::{params, params_from_iter, Connection, ErrorCode, OpenFlags, Row};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How many of the newest runs starting with a prefix `suggest` ranks when
+/// there are more
+const SUGGEST_CANDIDATES: usize = 1000;
+
+/// How often every command line followed another in the same session, as
+/// kept in `command_line_transitions`
+const COUNT_TRANSITIONS: &str = "SELECT previous, command_line AS next, count(1) AS count,
+    max(timestamp) AS last_seen FROM (
+        SELECT command_line, timestamp,
+        lag(command_line) OVER (PARTITION BY session_id ORDER BY timestamp) AS previous
+        FROM history_items
+    ) WHERE previous IS NOT NULL
+    GROUP BY previous, command_line";
 
 pub trait Database {
     fn save(&mut self, h: &HistoryItem) -> Result<()>;
     fn save_bulk(&mut self, h: &[HistoryItem]) -> Result<()>;
+    /// The history item with its `metadata`
     fn load(&self, id: &str) -> Result<HistoryItem>;
+    /// What else is known about a history item, see `HistoryItem::metadata`
+    fn metadata(&self, history_id: i64) -> Result<BTreeMap<String, Vec<String>>>;
     fn list(&self, max: Option<usize>, unique: bool) -> Result<Vec<HistoryItem>>;
+    /// The newest row of every distinct `key`, newest first
+    fn list_unique(&self, max: Option<usize>, key: UniqueKey) -> Result<Vec<HistoryItem>>;
     fn range(
         &self,
         from: chrono::DateTime<Utc>,
@@ -29,22 +63,83 @@ pub trait Database {
         limit: Option<i64>,
         search_mode: SearchMode,
         query: &str,
-    ) -> Result<Vec<HistoryItem>>;
+    ) -> Result<Vec<SearchHit>>;
+    /// Search with ranking and the other options in `options`
+    fn search_ranked(&self, query: &str, options: &SearchOptions) -> Result<Vec<SearchHit>>;
+    /// Search with a boolean query, whatever `options.mode` is
+    fn search_query(&self, query: &Query, options: &SearchOptions) -> Result<Vec<SearchHit>>;
+    /// The single most likely command line starting with `prefix`, for
+    /// inline suggestions as the user types. Cheap enough to call on every
+    /// keystroke, as a short prefix only ranks the command lines of its
+    /// newest runs. The `follows` weight isn't used.
+    fn suggest(
+        &self,
+        prefix: &str,
+        context: &SearchContext,
+        weights: &ContextWeights,
+    ) -> Result<Option<String>>;
+    /// The command lines that followed `last_command_line` in the same
+    /// session, most likely first
+    fn predict_next(&self, last_command_line: &str) -> Result<Vec<Prediction>>;
+    /// The newest row of every command, ranked by `ranking`
+    fn list_ranked(&self, max: Option<usize>, ranking: &Ranking) -> Result<Vec<SearchHit>>;
     fn query_history(&self, query: &str) -> Result<Vec<HistoryItem>>;
     fn delete_history_item(&self, id: i64) -> Result<i64>;
+    fn select_where(&self, filter: &HistoryFilter) -> Result<Vec<HistoryItem>>;
+    /// The rows `update_where` would change: those matching `filter` that
+    /// `changes` makes different without colliding with another row
+    fn select_update(
+        &self,
+        filter: &HistoryFilter,
+        changes: &HistoryUpdate,
+    ) -> Result<Vec<HistoryItem>>;
+    /// Change the rows `select_update` returns, or only count them when
+    /// `dry_run` is set, and return how many changed
+    fn update_where(
+        &self,
+        filter: &HistoryFilter,
+        changes: &HistoryUpdate,
+        dry_run: bool,
+    ) -> Result<usize>;
+    fn delete_where(&self, filter: &HistoryFilter, dry_run: bool) -> Result<usize>;
+    /// Run `f` as a single unit: everything it did is committed when it
+    /// returns `Ok` and rolled back when it returns `Err`.
+    fn transaction<T, F>(&mut self, f: F) -> Result<T>
+    where
+        Self: Sized,
+        F: FnOnce(&mut Self) -> Result<T>;
 }
 
 pub struct Sqlite {
     conn: Connection,
     sql_log_mode: SqlLogMode,
+    open_mode: OpenMode,
+    notifier: Arc<Mutex<Notifier>>,
+    /// Schema names of the databases added with `attach`
+    attached: Vec<String>,
 }
 
 impl Sqlite {
     pub fn new(path: impl AsRef<Path>, sql_log_mode: SqlLogMode) -> Result<Self> {
+        Self::open(path, sql_log_mode, OpenMode::ReadWrite)
+    }
+
+    pub fn open(
+        path: impl AsRef<Path>,
+        sql_log_mode: SqlLogMode,
+        open_mode: OpenMode,
+    ) -> Result<Self> {
         let path = path.as_ref();
-        debug!("opening sqlite database at {:?}", path);
+        debug!("opening sqlite database at {:?} as {}", path, open_mode);
 
         let create = !path.exists();
+        if create && open_mode != OpenMode::ReadWrite {
+            bail!(
+                "{:?} does not exist, it can't be opened {}",
+                path,
+                open_mode
+            );
+        }
         if create {
             if let Some(dir) = path.parent() {
                 std::fs::create_dir_all(dir)?;
@@ -55,27 +150,210 @@ impl Sqlite {
         // * https://github.com/ivanceras/r2d2-sqlite
         // * https://lib.rs/crates/serde_rusqlite
 
-        let mut conn = Connection::open(format!("file:{}", path.as_os_str().to_str().unwrap()))?;
+        let uri = format!("file:{}", path.as_os_str().to_str().unwrap());
+        let mut conn = match open_mode {
+            OpenMode::ReadWrite => Connection::open(uri)?,
+            OpenMode::ReadOnly | OpenMode::Snapshot => match Self::open_read_only(&uri, false) {
+                // immutable skips the -wal and all locking, which is only
+                // safe when nobody writes to the file, so it is never chosen
+                // for the caller
+                Err(rusqlite::Error::SqliteFailure(e, _))
+                    if matches!(e.code, ErrorCode::CannotOpen | ErrorCode::ReadOnly) =>
+                {
+                    bail!(
+                        "{:?} can't be opened {}: {}. If nothing writes to it any more \
+                        (e.g. a backup on a read only mount), open it immutable",
+                        path,
+                        open_mode,
+                        e
+                    )
+                }
+                conn => conn?,
+            },
+            OpenMode::Immutable => {
+                let mut wal = path.as_os_str().to_owned();
+                wal.push("-wal");
+                if std::fs::metadata(&wal).is_ok_and(|m| m.len() > 0) {
+                    bail!(
+                        "{:?} has a write ahead log that immutable would skip, open it readonly",
+                        path
+                    );
+                }
+                Self::open_read_only(&uri, true)?
+            }
+        };
         set_log_mode(&mut conn, sql_log_mode);
+        add_regexp_function(&conn)?;
+        add_casefold_function(&conn)?;
+
+        match open_mode {
+            OpenMode::ReadWrite => {
+                //https://sqlite.org/pragma.html#pragma_journal_mode
+                //https://www.sqlite.org/pragma.html#pragma_busy_timeout - no clue if 1000 is right
+                conn.execute_batch(
+                    "
+                    PRAGMA page_size=32768;
+                    PRAGMA journal_mode=wal;
+                    PRAGMA wal_autocheckpoint=32;
+                    PRAGMA journal_size_limit=3145728;
+                    PRAGMA foreign_keys=ON;
+                    PRAGMA busy_timeout = 1000;
+                    ",
+                )?;
+
+                Self::setup_db(&conn)?;
+            }
+            OpenMode::ReadOnly | OpenMode::Snapshot | OpenMode::Immutable => {
+                // nothing here may write, so the schema is used as it is
+                conn.execute_batch(
+                    "
+                    PRAGMA query_only=ON;
+                    PRAGMA busy_timeout = 1000;
+                    ",
+                )?;
+            }
+        }
+
+        let db = Self {
+            conn,
+            sql_log_mode,
+            open_mode,
+            notifier: Arc::new(Mutex::new(Notifier::default())),
+            attached: Vec::new(),
+        };
+        db.begin_snapshot()?;
 
-        //https://sqlite.org/pragma.html#pragma_journal_mode
-        //https://www.sqlite.org/pragma.html#pragma_busy_timeout - no clue if 1000 is right
-        conn.execute_batch(
-            "
-            PRAGMA page_size=32768;
-            PRAGMA journal_mode=wal;
-            PRAGMA wal_autocheckpoint=32;
-            PRAGMA journal_size_limit=3145728;
-            PRAGMA foreign_keys=ON;
-            PRAGMA busy_timeout = 1000;
-            ",
+        Ok(db)
+    }
+
+    /// Open `uri` read only, reading the schema so that a file that can't
+    /// be read fails here rather than on the first query
+    fn open_read_only(uri: &str, immutable: bool) -> rusqlite::Result<Connection> {
+        let conn = Connection::open_with_flags(
+            format!(
+                "{}?mode=ro{}",
+                uri,
+                if immutable { "&immutable=1" } else { "" }
+            ),
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
         )?;
+        conn.query_row("select count(*) from sqlite_master", [], |_| Ok(()))?;
+        Ok(conn)
+    }
+
+    /// Attach another history database, read only, under `name`. From then
+    /// on `search`, `list`, `list_unique`, `range`, `history_count`,
+    /// `first`, `last`, `before` and `suggest` cover it as well, and every
+    /// row they return has `source` set to `main` or to `name`.
+    ///
+    /// Row ids and writes belong to the main database, so `load`,
+    /// `metadata`, `select_where`, `update_where` and `delete_where` only
+    /// see its rows, and so do `predict_next` and the follows count of the
+    /// context ranking, which are kept as it is written to.
+    ///
+    /// In `Snapshot` mode this starts a new snapshot.
+    pub fn attach(&mut self, path: impl AsRef<Path>, name: &str) -> Result<()> {
+        let path = path.as_ref();
+        debug!("attaching {:?} as {}", path, name);
+
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            bail!("{:?} is not a valid database name", name);
+        }
+        if name == "main" || name == "temp" || self.attached.iter().any(|a| a == name) {
+            bail!("a database named {} is already attached", name);
+        }
+        if !path.exists() {
+            bail!("{:?} does not exist", path);
+        }
+
+        self.end_snapshot()?;
+        let result = self.conn.execute(
+            format!("ATTACH DATABASE ?1 AS \"{}\"", name).as_str(),
+            [format!(
+                "file:{}?mode=ro",
+                path.as_os_str().to_str().unwrap()
+            )],
+        );
+        self.begin_snapshot()?;
 
-        Self::setup_db(&conn)?;
-        Ok(Self { conn, sql_log_mode })
+        result?;
+        self.attached.push(name.to_string());
+        Ok(())
     }
 
-    fn setup_db(conn: &Connection) -> Result<usize> {
+    pub fn detach(&mut self, name: &str) -> Result<()> {
+        let idx = match self.attached.iter().position(|a| a == name) {
+            Some(idx) => idx,
+            None => bail!("no database named {} is attached", name),
+        };
+
+        self.end_snapshot()?;
+        let result = self
+            .conn
+            .execute_batch(format!("DETACH DATABASE \"{}\"", name).as_str());
+        self.begin_snapshot()?;
+
+        result?;
+        self.attached.remove(idx);
+        Ok(())
+    }
+
+    pub fn attached(&self) -> &[String] {
+        &self.attached
+    }
+
+    // attaching isn't allowed inside a transaction
+    fn end_snapshot(&self) -> Result<()> {
+        if self.open_mode == OpenMode::Snapshot {
+            self.conn.execute_batch("COMMIT")?;
+        }
+        Ok(())
+    }
+
+    fn begin_snapshot(&self) -> Result<()> {
+        if self.open_mode == OpenMode::Snapshot {
+            // a read transaction sees the database as it was when it first
+            // read, so keep one open for as long as the connection lives
+            self.conn.execute_batch("BEGIN DEFERRED")?;
+            self.conn
+                .query_row("select count(1) from sqlite_master", [], |r| {
+                    r.get::<_, i64>(0)
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Prefix `sql` so that `history_items` in it means the rows of the main
+    /// database and of every attached one, with a `source` column naming
+    /// where each row came from.
+    fn with_attached(&self, sql: &str) -> String {
+        if self.attached.is_empty() {
+            return sql.to_string();
+        }
+
+        let columns = "history_id, timestamp, duration, exit_status, command_line, command, \
+            command_params, cwd, session_id, run_count";
+        let union = std::iter::once("main")
+            .chain(self.attached.iter().map(|a| a.as_str()))
+            .map(|name| {
+                format!(
+                    "select '{name}' as source, {columns} from \"{name}\".history_items",
+                    name = name,
+                    columns = columns
+                )
+            })
+            .join(" union all ");
+
+        format!("with history_items as ({}) {}", union, sql)
+    }
+
+    pub fn open_mode(&self) -> OpenMode {
+        self.open_mode
+    }
+
+    fn setup_db(conn: &Connection) -> Result<()> {
         debug!("running sqlite database setup");
 
         let history_table = r#"
@@ -95,9 +373,153 @@ impl Sqlite {
         );
 
         CREATE INDEX IF NOT EXISTS idx_history_timestamp on history_items(timestamp);
-        CREATE INDEX IF NOT EXISTS idx_history_command on history_items(command);"#;
+        CREATE INDEX IF NOT EXISTS idx_history_command on history_items(command);
+        CREATE INDEX IF NOT EXISTS idx_history_cwd on history_items(cwd);
+        CREATE INDEX IF NOT EXISTS idx_history_suggest on history_items(
+            command_line, timestamp, exit_status, cwd, session_id, run_count
+        );
+        CREATE INDEX IF NOT EXISTS idx_history_session on history_items(session_id, timestamp);"#;
+
+        // How often each command line followed another in the same session,
+        // read by `predict_next`. Every insert counts one transition from the
+        // command line before it in its session; deletes don't take any back.
+        let transitions_table = r#"
+        CREATE TABLE IF NOT EXISTS command_line_transitions (
+            previous  TEXT NOT NULL,
+            next      TEXT NOT NULL,
+            count     INTEGER NOT NULL,
+            last_seen INTEGER NOT NULL,
+
+            PRIMARY KEY (previous, next)
+        ) WITHOUT ROWID;
+
+        CREATE TRIGGER IF NOT EXISTS trg_history_line_transition AFTER INSERT ON history_items
+        BEGIN
+            INSERT INTO command_line_transitions (previous, next, count, last_seen)
+            SELECT p.command_line, new.command_line, 1, new.timestamp FROM history_items p
+            WHERE p.session_id = new.session_id AND p.timestamp < new.timestamp
+            ORDER BY p.timestamp DESC LIMIT 1
+            ON CONFLICT (previous, next) DO UPDATE
+            SET count = count + 1, last_seen = max(last_seen, excluded.last_seen);
+        END;"#;
+
+        // databases from before the transitions were kept get them counted
+        // from their history once. The first version counted commands, not
+        // command lines, and is dropped.
+        let backfill_transitions = format!(
+            r#"
+        DROP TRIGGER IF EXISTS trg_history_transition;
+        DROP TABLE IF EXISTS command_transitions;
+        INSERT INTO command_line_transitions (previous, next, count, last_seen) {};"#,
+            COUNT_TRANSITIONS
+        );
+
+        // The trigrams of every command line, case folded, so that a typo
+        // search only has to look at command lines sharing enough of them
+        // with the query. Rows are added on save, and removed with the last
+        // history_items row of their command line.
+        let trigrams_table = r#"
+        CREATE TABLE IF NOT EXISTS command_trigrams (
+            trigram      TEXT NOT NULL,
+            command_line TEXT NOT NULL,
+
+            PRIMARY KEY (trigram, command_line)
+        ) WITHOUT ROWID;
+
+        CREATE INDEX IF NOT EXISTS idx_command_trigrams_line on command_trigrams(command_line);
+
+        CREATE TRIGGER IF NOT EXISTS trg_history_trigrams_delete AFTER DELETE ON history_items
+        WHEN NOT EXISTS (SELECT 1 FROM history_items WHERE command_line = old.command_line)
+        BEGIN
+            DELETE FROM command_trigrams WHERE command_line = old.command_line;
+        END;
 
-        Ok(conn.execute(history_table, [])?)
+        CREATE TRIGGER IF NOT EXISTS trg_history_trigrams_update
+        AFTER UPDATE OF command_line ON history_items
+        WHEN old.command_line <> new.command_line
+        AND NOT EXISTS (SELECT 1 FROM history_items WHERE command_line = old.command_line)
+        BEGIN
+            DELETE FROM command_trigrams WHERE command_line = old.command_line;
+        END;"#;
+
+        // `HistoryItem::metadata`, one row per value, kept in the order the
+        // values were added
+        let metadata_table = r#"
+        CREATE TABLE IF NOT EXISTS history_metadata (
+            history_id INTEGER NOT NULL REFERENCES history_items(history_id) ON DELETE CASCADE,
+            key        TEXT NOT NULL,
+            value      TEXT NOT NULL
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_history_metadata on history_metadata(history_id);"#;
+
+        // Change log read by `poll_changes` so that other processes can see
+        // what a writer touched. Only the newest 10000 changes are kept.
+        let changes_table = r#"
+        CREATE TABLE IF NOT EXISTS history_changes (
+            change_id  INTEGER PRIMARY KEY AUTOINCREMENT,
+            history_id INTEGER NOT NULL,
+            action     TEXT NOT NULL
+        );
+
+        CREATE TRIGGER IF NOT EXISTS trg_history_insert AFTER INSERT ON history_items
+        BEGIN
+            INSERT INTO history_changes (history_id, action) VALUES (new.history_id, 'inserted');
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS trg_history_update AFTER UPDATE ON history_items
+        BEGIN
+            INSERT INTO history_changes (history_id, action) VALUES (new.history_id, 'updated');
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS trg_history_delete AFTER DELETE ON history_items
+        BEGIN
+            INSERT INTO history_changes (history_id, action) VALUES (old.history_id, 'deleted');
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS trg_history_changes_prune AFTER INSERT ON history_changes
+        WHEN new.change_id % 1000 = 0
+        BEGIN
+            DELETE FROM history_changes WHERE change_id <= new.change_id - 10000;
+        END;"#;
+
+        conn.execute_batch(history_table)?;
+        conn.execute_batch(metadata_table)?;
+        conn.execute_batch(changes_table)?;
+
+        if Self::has_table(conn, "command_line_transitions")? {
+            conn.execute_batch(transitions_table)?;
+        } else {
+            conn.execute_batch(&format!(
+                "BEGIN; {} {} COMMIT;",
+                transitions_table, backfill_transitions
+            ))?;
+        }
+
+        // databases from before the trigrams were kept get every command
+        // line they have indexed once
+        if Self::has_table(conn, "command_trigrams")? {
+            conn.execute_batch(trigrams_table)?;
+        } else {
+            conn.execute_batch("BEGIN")?;
+            let backfill = (|| -> Result<()> {
+                conn.execute_batch(trigrams_table)?;
+                let mut stmt = conn.prepare("select distinct command_line from history_items")?;
+                let mut rows = stmt.query([])?;
+                while let Some(row) = rows.next()? {
+                    Self::index_trigrams(conn, row.get_ref(0)?.as_str()?)?;
+                }
+                Ok(())
+            })();
+            match backfill {
+                Ok(()) => conn.execute_batch("COMMIT")?,
+                Err(e) => {
+                    let _ = conn.execute_batch("ROLLBACK");
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
 
         // let performance_table = r#"
         // CREATE TABLE IF NOT EXISTS performance_items (
@@ -111,18 +533,310 @@ impl Sqlite {
         // Ok(conn.execute(performance_table, [])?)
     }
 
-    fn save_raw(tx: &mut Transaction, h: &HistoryItem) -> Result<usize> {
+    /// Subscribe to changes made to `history_items`.
+    ///
+    /// Changes made through this connection are delivered as soon as their
+    /// transaction commits. Changes made by other connections or processes are
+    /// delivered by `poll_changes`, which should be called periodically.
+    ///
+    /// Only the newest 10000 changes are kept, so a subscriber that polls too
+    /// rarely may miss some; it then receives a `HistoryAction::Gap` event.
+    ///
+    /// The changes are logged to `history_changes` by triggers whether or
+    /// not anything subscribes, as the subscriber may be another process.
+    /// That is a row written for every row inserted, updated or deleted,
+    /// which an import of a big history pays for too.
+    pub fn subscribe(&self) -> Result<Receiver<HistoryEvent>> {
+        let (tx, rx) = channel();
+        let mut notifier = self.notifier.lock().unwrap();
+
+        if notifier.subscribers.is_empty() {
+            notifier.data_version = self.data_version()?;
+            notifier.change_cursor = self.conn.query_row(
+                "select coalesce(max(change_id), 0) from history_changes",
+                [],
+                |r| r.get(0),
+            )?;
+            if !notifier.hooks_installed {
+                self.install_hooks();
+                notifier.hooks_installed = true;
+            }
+        }
+        notifier.subscribers.push(tx);
+
+        Ok(rx)
+    }
+
+    /// Deliver changes committed by other connections since the last poll,
+    /// returning how many events were sent to subscribers.
+    pub fn poll_changes(&self) -> Result<usize> {
+        let version = self.data_version()?;
+        let mut notifier = self.notifier.lock().unwrap();
+        if notifier.subscribers.is_empty() || version == notifier.data_version {
+            return Ok(0);
+        }
+        notifier.data_version = version;
+
+        let mut sent = 0;
+        // change ids have no holes, so a later first id means the changes
+        // after the cursor were pruned before we got to them
+        let first: Option<i64> = self.conn.query_row(
+            "select min(change_id) from history_changes where change_id > ?1",
+            [notifier.change_cursor],
+            |r| r.get(0),
+        )?;
+        if matches!(first, Some(first) if first > notifier.change_cursor + 1) {
+            notifier.dispatch(HistoryEvent {
+                action: HistoryAction::Gap,
+                history_id: None,
+            });
+            sent += 1;
+        }
+
+        let mut stmt = self.conn.prepare(
+            "select change_id, history_id, action from history_changes
+            where change_id > ?1 order by change_id asc",
+        )?;
+        let rows = stmt.query_map([notifier.change_cursor], |r| {
+            Ok((
+                r.get::<_, i64>(0)?,
+                r.get::<_, i64>(1)?,
+                r.get::<_, String>(2)?,
+            ))
+        })?;
+
+        for row in rows {
+            let (change_id, history_id, action) = row?;
+            notifier.change_cursor = change_id;
+            // already delivered by the update hook
+            if notifier.own_changes.remove(&change_id) {
+                continue;
+            }
+            if let Ok(action) = action.parse() {
+                notifier.dispatch(HistoryEvent {
+                    action,
+                    history_id: Some(history_id),
+                });
+                sent += 1;
+            }
+        }
+        let cursor = notifier.change_cursor;
+        notifier.own_changes.retain(|id| *id > cursor);
+        drop(stmt);
+        self.release_hooks(&mut notifier);
+
+        Ok(sent)
+    }
+
+    fn data_version(&self) -> Result<i64> {
+        Ok(self
+            .conn
+            .query_row("PRAGMA data_version", [], |r| r.get(0))?)
+    }
+
+    fn install_hooks(&self) {
+        let notifier = Arc::clone(&self.notifier);
+        self.conn
+            .update_hook(Some(move |action, _db: &str, table: &str, rowid| {
+                let mut notifier = notifier.lock().unwrap();
+                if notifier.subscribers.is_empty() {
+                    return;
+                }
+                match table {
+                    "history_items" => {
+                        let action = match action {
+                            Action::SQLITE_INSERT => HistoryAction::Inserted,
+                            Action::SQLITE_UPDATE => HistoryAction::Updated,
+                            Action::SQLITE_DELETE => HistoryAction::Deleted,
+                            _ => return,
+                        };
+                        notifier.pending.push(HistoryEvent {
+                            action,
+                            history_id: Some(rowid),
+                        });
+                    }
+                    "history_changes" if action == Action::SQLITE_INSERT => {
+                        notifier.pending_changes.push(rowid);
+                    }
+                    _ => {}
+                }
+            }));
+
+        // the commit can still fail after this hook, so the events only
+        // go out once it has returned
+        let notifier = Arc::clone(&self.notifier);
+        self.conn.commit_hook(Some(move || {
+            let mut notifier = notifier.lock().unwrap();
+            let events = std::mem::take(&mut notifier.pending);
+            notifier.committed.extend(events);
+            let changes = std::mem::take(&mut notifier.pending_changes);
+            notifier.committed_changes.extend(changes);
+            // returning true would turn the commit into a rollback
+            false
+        }));
+
+        let notifier = Arc::clone(&self.notifier);
+        self.conn.rollback_hook(Some(move || {
+            let mut notifier = notifier.lock().unwrap();
+            notifier.pending.clear();
+            notifier.pending_changes.clear();
+            notifier.committed.clear();
+            notifier.committed_changes.clear();
+        }));
+    }
+
+    /// Send the events of the last commit to subscribers, once it has
+    /// succeeded. Called after every write that commits.
+    fn deliver_committed(&self) {
+        let mut notifier = self.notifier.lock().unwrap();
+        if !self.conn.is_autocommit() {
+            return;
+        }
+        for event in std::mem::take(&mut notifier.committed) {
+            notifier.dispatch(event);
+        }
+        let changes = std::mem::take(&mut notifier.committed_changes);
+        if !notifier.subscribers.is_empty() {
+            notifier.own_changes.extend(changes);
+        }
+    }
+
+    /// Remove the hooks once every receiver has gone away. The hooks can't
+    /// do this themselves, so it happens on the next poll or write.
+    fn release_hooks(&self, notifier: &mut Notifier) {
+        if notifier.hooks_installed && notifier.subscribers.is_empty() {
+            self.conn.update_hook(None::<fn(Action, &str, &str, i64)>);
+            self.conn.commit_hook(None::<fn() -> bool>);
+            self.conn.rollback_hook(None::<fn()>);
+            notifier.hooks_installed = false;
+            notifier.pending.clear();
+            notifier.pending_changes.clear();
+            notifier.committed.clear();
+            notifier.committed_changes.clear();
+            notifier.own_changes.clear();
+        }
+    }
+
+    /// Start a transaction, or a savepoint when one is already open so that
+    /// writes made inside `transaction`/`begin` become part of it.
+    fn begin_scope(&self) -> Result<Scope> {
+        let savepoint = !self.conn.is_autocommit();
+        let mut notifier = self.notifier.lock().unwrap();
+        if savepoint {
+            self.conn.execute_batch("SAVEPOINT rsq")?;
+        } else {
+            self.release_hooks(&mut notifier);
+            self.conn.execute_batch("BEGIN DEFERRED")?;
+        }
+
+        Ok(Scope {
+            savepoint,
+            pending: notifier.pending.len(),
+            pending_changes: notifier.pending_changes.len(),
+        })
+    }
+
+    fn end_scope(&self, scope: Scope, commit: bool) -> Result<()> {
+        match (scope.savepoint, commit) {
+            (true, true) => self.conn.execute_batch("RELEASE rsq")?,
+            (true, false) => {
+                self.conn.execute_batch("ROLLBACK TO rsq; RELEASE rsq")?;
+                // the rollback hook only fires for the outermost transaction
+                let mut notifier = self.notifier.lock().unwrap();
+                notifier.pending.truncate(scope.pending);
+                notifier.pending_changes.truncate(scope.pending_changes);
+            }
+            (false, true) => {
+                if let Err(e) = self.conn.execute_batch("COMMIT") {
+                    let _ = self.conn.execute_batch("ROLLBACK");
+                    let mut notifier = self.notifier.lock().unwrap();
+                    notifier.committed.clear();
+                    notifier.committed_changes.clear();
+                    return Err(e.into());
+                }
+                self.deliver_committed();
+            }
+            (false, false) => self.conn.execute_batch("ROLLBACK")?,
+        }
+
+        Ok(())
+    }
+
+    /// Run `f` in a scope that is committed when `commit` is true and `f`
+    /// succeeds, and rolled back otherwise.
+    fn in_scope<T>(&self, commit: bool, f: impl FnOnce(&Connection) -> Result<T>) -> Result<T> {
+        let scope = self.begin_scope()?;
+        match f(&self.conn) {
+            Ok(v) => {
+                self.end_scope(scope, commit)?;
+                Ok(v)
+            }
+            Err(e) => {
+                let _ = self.end_scope(scope, false);
+                Err(e)
+            }
+        }
+    }
+
+    /// Open a transaction handle. Every `Database` operation is available
+    /// through it, and nothing is kept unless `commit` is called; dropping
+    /// the handle rolls back.
+    pub fn begin(&mut self) -> Result<SqliteTransaction<'_>> {
+        let scope = self.begin_scope()?;
+        Ok(SqliteTransaction {
+            db: self,
+            scope: Some(scope),
+        })
+    }
+
+    /// Whether the main database has the table `name`, which databases
+    /// from before it was added that are opened read only may not
+    fn has_table(conn: &Connection, name: &str) -> Result<bool> {
+        Ok(conn.query_row(
+            "select exists(select 1 from main.sqlite_master where type = 'table' and name = ?1)",
+            [name],
+            |r| r.get(0),
+        )?)
+    }
+
+    /// Add the trigrams of `command_line` to `command_trigrams`
+    fn index_trigrams(tx: &Connection, command_line: &str) -> Result<()> {
+        let mut stmt = tx.prepare_cached(
+            "insert or ignore into command_trigrams (trigram, command_line) values (?1, ?2)",
+        )?;
+        for trigram in trigrams(&fold_case(command_line)) {
+            stmt.execute(params![trigram, command_line])?;
+        }
+        Ok(())
+    }
+
+    fn save_raw(tx: &Connection, h: &HistoryItem) -> Result<usize> {
         let cmd_params = match h.command_params.as_ref() {
-            Some(p) => &p,
+            Some(p) => p,
             None => "",
         };
 
         // We don't need the history_id here because it's an auto number field
         // so it should be ever increasing
-        Ok(tx.execute(
+        let inserted = tx.execute(
             "insert or ignore into history_items (history_id, command_line, command, command_params, cwd, duration, exit_status, session_id, timestamp, run_count) values(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
             params![h.history_id, h.command_line.as_str(), h.command.as_str(), cmd_params, h.cwd.as_str(), h.duration, h.exit_status, h.session_id, h.timestamp.timestamp_nanos(), h.run_count]
-        )?)
+        )?;
+        if inserted > 0 {
+            let history_id = tx.last_insert_rowid();
+            Self::index_trigrams(tx, &h.command_line)?;
+            if !h.metadata.is_empty() {
+                let mut stmt = tx.prepare_cached(
+                    "insert into history_metadata (history_id, key, value) values (?1, ?2, ?3)",
+                )?;
+                for (key, values) in &h.metadata {
+                    for value in values {
+                        stmt.execute(params![history_id, key, value])?;
+                    }
+                }
+            }
+        }
+        Ok(inserted)
     }
 
     // fn convert_time(h: &HistoryItem) {
@@ -158,10 +872,279 @@ impl Sqlite {
             session_id: row.get("session_id")?,
             timestamp: Utc.timestamp_nanos(row.get("timestamp")?),
             run_count: row.get("run_count")?,
+            // only present when other databases are attached
+            source: row.get("source").ok(),
+            metadata: BTreeMap::new(),
         };
         debug!("HistoryItem: {:#?}", &h);
         Ok(h)
     }
+
+    /// A typo tolerant search of command lines. Only the ones sharing
+    /// enough trigrams with the query are read, or containing it when it's
+    /// too short to have any, unless attached databases, or one opened read
+    /// only without `command_trigrams`, have to be searched as well.
+    fn search_typo(&self, query: &str, options: &SearchOptions) -> Result<Vec<SearchHit>> {
+        let folded = fold_case(query);
+        let query_trigrams = trigrams(&folded);
+        let min_shared =
+            min_shared_trigrams(query_trigrams.len(), max_typos(folded.chars().count()));
+        let mut values: Vec<Value> = vec![];
+        let matches = match min_shared {
+            Some(min_shared)
+                if self.attached.is_empty() && Self::has_table(&self.conn, "command_trigrams")? =>
+            {
+                values.extend(query_trigrams.into_iter().map(Value::from));
+                values.push((min_shared as i64).into());
+                format!(
+                    "{{t}}command_line in (
+                select command_line from command_trigrams where trigram in ({})
+                group by command_line having count(1) >= ?{}
+            )",
+                    (1..values.len()).map(|i| format!("?{}", i)).join(", "),
+                    values.len()
+                )
+            }
+            // too short for typos
+            None => {
+                values.push(folded.into());
+                "instr(casefold({t}command_line), ?1) > 0".to_string()
+            }
+            _ => "1".to_string(),
+        };
+        let matcher = Matcher::new(query, SearchMode::Typo, false)?;
+        let options = SearchOptions {
+            field: SearchField::CommandLine,
+            ..options.clone()
+        };
+
+        self.search_with(values, matches, matcher, &options)
+    }
+
+    /// The part of `search_ranked` and `search_query` that's the same for
+    /// every kind of query, once the `where` clause picking the matching
+    /// rows is known. `{t}` in it stands for the table the columns are in,
+    /// and its parameters are `values`.
+    fn search_with(
+        &self,
+        mut values: Vec<Value>,
+        matches: String,
+        matcher: Matcher,
+        options: &SearchOptions,
+    ) -> Result<Vec<SearchHit>> {
+        // only when the rows come out of sqlite in their final order can it
+        // apply the limit
+        let ordered = options.ranking == Ranking::Recency && !options.mode.is_scored();
+        let limit = match (ordered, options.limit) {
+            (true, Some(l)) => format!("limit {}", l),
+            _ => "".to_string(),
+        };
+
+        // how often each command ran in the given cwd and session, and right
+        // after the given command, only matters to the context ranking
+        let context = match (&options.ranking, &options.context) {
+            (Ranking::Context(_), Some(context)) => context.clone(),
+            _ => SearchContext::default(),
+        };
+        let cwd_runs = match context.cwd {
+            Some(cwd) => {
+                values.push(cwd.into());
+                format!("sum(cwd = ?{})", values.len())
+            }
+            None => "sum(0)".to_string(),
+        };
+        let session_runs = match context.session_id {
+            Some(session_id) => {
+                values.push(session_id.into());
+                format!("sum(session_id = ?{})", values.len())
+            }
+            None => "sum(0)".to_string(),
+        };
+        // how often each command line followed the last one, as kept for
+        // `predict_next`, and counted the same way when it isn't kept
+        let (follows, follows_join) = match context.last_command_line {
+            Some(last_command_line) => {
+                values.push(last_command_line.into());
+                let transitions = match Self::has_table(&self.conn, "command_line_transitions")? {
+                    true => "command_line_transitions".to_string(),
+                    false => format!("({})", COUNT_TRANSITIONS),
+                };
+                (
+                    "coalesce(f.count, 0)".to_string(),
+                    format!(
+                        "left join {} f on f.previous = ?{} and h.command_line = f.next",
+                        transitions,
+                        values.len()
+                    ),
+                )
+            }
+            None => ("0".to_string(), "".to_string()),
+        };
+
+        // only matching rows with a wanted exit status and cwd count, so
+        // that e.g. the newest failure of a command is found even when it has
+        // succeeded since, or its newest run in a directory even when it has
+        // run elsewhere since. `{t}` stands for the table the columns are in.
+        let mut wanted: Vec<String> = vec![matches];
+        if let Some(clause) = options.exit_status.to_sql("{t}exit_status") {
+            wanted.push(clause);
+        }
+        if let Some(dir) = &options.cwd_under {
+            wanted.push(cwd_under_sql("{t}cwd", &cwd_forms(dir), 0, &mut values));
+        }
+        let wanted = wanted.join(" and ");
+
+        // the newest row of every command, or other unique key, with how
+        // often it ran when the ranking needs to know. Counting means reading
+        // every row of the group, so a recency search leaves it out.
+        let (stats, stat_columns) = match options.ranking {
+            Ranking::Recency => ("".to_string(), "".to_string()),
+            _ => (
+                format!(
+                    ", count(1) over same_key as executions,
+                sum(run_count) over same_key as total_runs,
+                {} over same_key as cwd_runs, {} over same_key as session_runs",
+                    cwd_runs, session_runs
+                ),
+                format!(", {} as follows", follows),
+            ),
+        };
+        let mut stmt = self.prepare(
+            self.with_attached(&format!(
+                "select h.*{}
+            from {}
+            {}
+            order by h.timestamp desc {}",
+                stat_columns,
+                newest_rows_sql(options.unique_by, &wanted.replace("{t}", ""), &stats),
+                follows_join,
+                limit
+            ))
+            .as_str(),
+        )?;
+
+        let now = Utc::now();
+        let mut hits: Vec<SearchHit> = Vec::new();
+        let mut rows = stmt.query(params_from_iter(values))?;
+        while let Some(row) = rows.next()? {
+            let item = Self::query_history(row)?;
+            // the field that matched best, the first one on a tie
+            let found = options
+                .field
+                .fields()
+                .iter()
+                .filter_map(|field| {
+                    let (score, matches) = matcher.find(field.text(&item))?;
+                    Some((*field, score, matches))
+                })
+                .fold(
+                    None::<(SearchField, i64, Vec<Range<usize>>)>,
+                    |best, found| match best {
+                        Some(best) if best.1 >= found.1 => Some(best),
+                        _ => Some(found),
+                    },
+                );
+            let (field, fuzzy_score, matches) = match found {
+                Some((field, score, matches)) if options.mode.is_scored() => {
+                    (field, Some(score as f64), matches)
+                }
+                Some((field, _, matches)) => (field, None, matches),
+                None if options.mode.is_scored() => continue,
+                None => (options.field.fields()[0], None, vec![]),
+            };
+
+            // a frequent command that barely matches shouldn't outrank one
+            // that matches well
+            let quality = fuzzy_score.map_or(1.0, |score| matcher.quality(score as i64));
+            let score = match &options.ranking {
+                Ranking::Recency => {
+                    fuzzy_score.unwrap_or_else(|| item.timestamp.timestamp() as f64)
+                }
+                Ranking::Frecency(weights) => {
+                    weights.score(
+                        row.get("total_runs")?,
+                        row.get("executions")?,
+                        now - item.timestamp,
+                    ) * quality
+                }
+                Ranking::Context(weights) => {
+                    (weights.frecency.score(
+                        row.get("total_runs")?,
+                        row.get("executions")?,
+                        now - item.timestamp,
+                    ) + weights.cwd * row.get::<_, i64>("cwd_runs")? as f64
+                        + weights.session * row.get::<_, i64>("session_runs")? as f64
+                        + weights.follows * row.get::<_, i64>("follows")? as f64)
+                        * quality
+                }
+            };
+            hits.push(SearchHit {
+                item,
+                score,
+                field,
+                matches,
+            });
+        }
+
+        if !ordered {
+            // rows come out newest first and the sort is stable, so equal
+            // scores stay in order of recency
+            hits.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+            if let Some(limit) = options.limit {
+                hits.truncate(limit.max(0) as usize);
+            }
+        }
+
+        Ok(hits)
+    }
+}
+
+struct Scope {
+    savepoint: bool,
+    /// Length of `Notifier::pending` when the scope began
+    pending: usize,
+    /// Length of `Notifier::pending_changes` when the scope began
+    pending_changes: usize,
+}
+
+/// A transaction opened by `Sqlite::begin`
+pub struct SqliteTransaction<'a> {
+    db: &'a mut Sqlite,
+    scope: Option<Scope>,
+}
+
+impl SqliteTransaction<'_> {
+    pub fn commit(mut self) -> Result<()> {
+        let scope = self.scope.take().unwrap();
+        self.db.end_scope(scope, true)
+    }
+
+    pub fn rollback(mut self) -> Result<()> {
+        let scope = self.scope.take().unwrap();
+        self.db.end_scope(scope, false)
+    }
+}
+
+impl std::ops::Deref for SqliteTransaction<'_> {
+    type Target = Sqlite;
+
+    fn deref(&self) -> &Sqlite {
+        self.db
+    }
+}
+
+impl std::ops::DerefMut for SqliteTransaction<'_> {
+    fn deref_mut(&mut self) -> &mut Sqlite {
+        self.db
+    }
+}
+
+impl Drop for SqliteTransaction<'_> {
+    fn drop(&mut self) {
+        if let Some(scope) = self.scope.take() {
+            let _ = self.db.end_scope(scope, false);
+        }
+    }
 }
 
 impl std::ops::Deref for Sqlite {
@@ -172,9 +1155,119 @@ impl std::ops::Deref for Sqlite {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+/// What happened to a row in `history_items`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryAction {
+    Inserted,
+    Updated,
+    Deleted,
+    /// Changes were pruned before `poll_changes` could deliver them, so
+    /// anything cached from the history should be reloaded
+    Gap,
+}
+
+impl core::str::FromStr for HistoryAction {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inserted" => Ok(Self::Inserted),
+            "updated" => Ok(Self::Updated),
+            "deleted" => Ok(Self::Deleted),
+            "gap" => Ok(Self::Gap),
+            _ => Err("Could not parse HistoryAction"),
+        }
+    }
+}
+
+/// A change notification sent to subscribers of `Sqlite::subscribe`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEvent {
+    pub action: HistoryAction,
+    /// The row that changed, `None` for a `Gap`
+    pub history_id: Option<i64>,
+}
+
+#[derive(Default)]
+struct Notifier {
+    subscribers: Vec<Sender<HistoryEvent>>,
+    /// Events seen by the update hook in the transaction that is still open
+    pending: Vec<HistoryEvent>,
+    /// `history_changes` rows written by the transaction that is still open
+    pending_changes: Vec<i64>,
+    /// `pending` and `pending_changes` of the transaction being committed,
+    /// held back until the commit has succeeded, see `deliver_committed`
+    committed: Vec<HistoryEvent>,
+    committed_changes: Vec<i64>,
+    /// `history_changes` rows written by this connection, which `poll_changes`
+    /// must skip because the update hook already delivered them
+    own_changes: HashSet<i64>,
+    data_version: i64,
+    change_cursor: i64,
+    hooks_installed: bool,
+}
+
+impl Notifier {
+    fn dispatch(&mut self, event: HistoryEvent) {
+        // drop subscribers whose receiver has gone away
+        self.subscribers.retain(|s| s.send(event).is_ok());
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OpenMode {
+    /// Create the database if needed and allow writes
+    #[default]
+    ReadWrite,
+    /// Read an existing database without touching its schema or settings
+    ReadOnly,
+    /// Like `ReadOnly`, but every query sees the database as it was when it
+    /// was opened, even while other shells keep writing to it
+    Snapshot,
+    /// Like `ReadOnly`, for a file that nothing writes to any more, e.g. a
+    /// backup on a read only mount. Sqlite takes no locks and skips the
+    /// `-wal`, so opening a database that is still in use fails.
+    Immutable,
+}
+
+impl OpenMode {
+    pub fn variants() -> [&'static str; 4] {
+        ["readwrite", "readonly", "snapshot", "immutable"]
+    }
+}
+
+impl core::str::FromStr for OpenMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "readwrite" => Ok(Self::ReadWrite),
+            "readonly" => Ok(Self::ReadOnly),
+            "snapshot" => Ok(Self::Snapshot),
+            "immutable" => Ok(Self::Immutable),
+            _ => Err("Could not parse OpenMode"),
+        }
+    }
+}
+
+impl std::fmt::Display for OpenMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            OpenMode::ReadWrite => "readwrite",
+            OpenMode::ReadOnly => "readonly",
+            OpenMode::Snapshot => "snapshot",
+            OpenMode::Immutable => "immutable",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum SqlLogMode {
     /// Logging is disabled
+    #[default]
     Disabled,
     /// Records timings for each SQL statement
     Profile,
@@ -188,12 +1281,6 @@ impl SqlLogMode {
     }
 }
 
-impl Default for SqlLogMode {
-    fn default() -> Self {
-        Self::Disabled
-    }
-}
-
 impl core::str::FromStr for SqlLogMode {
     type Err = &'static str;
 
@@ -207,14 +1294,13 @@ impl core::str::FromStr for SqlLogMode {
     }
 }
 
-impl ToString for SqlLogMode {
-    fn to_string(&self) -> String {
-        match self {
+impl std::fmt::Display for SqlLogMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
             SqlLogMode::Disabled => "disabled",
             SqlLogMode::Profile => "profile",
             SqlLogMode::Trace => "trace",
-        }
-        .into()
+        })
     }
 }
 
@@ -247,25 +1333,472 @@ fn set_log_mode(connection: &mut Connection, sql_log_mode: SqlLogMode) {
     };
 }
 
-impl Database for Sqlite {
-    fn save(&mut self, h: &HistoryItem) -> Result<()> {
-        debug!("saving history to sqlite");
-        debug!("HistoryItem: {:#?}", &h);
-        let mut tx = self.conn.transaction()?;
-        Self::save_raw(&mut tx, h)?;
-        Ok(tx.commit()?)
-    }
-
-    fn save_bulk(&mut self, h: &[HistoryItem]) -> Result<()> {
-        debug!("saving history to sqlite");
-
-        let mut tx = self.conn.transaction()?;
+/// A `like` pattern (escaped with `\`) that every fuzzy match of `query`
+/// also matches. Without case sensitivity, both the query and the column
+/// must have been through `fold_case`.
+fn fuzzy_prefilter(query: &str) -> String {
+    query
+        .chars()
+        .map(|c| match c {
+            '%' | '_' | '\\' => format!("\\{}", c),
+            c => c.to_string(),
+        })
+        .join("%")
+}
 
-        for i in h {
-            Self::save_raw(&mut tx, i)?;
+/// The `where` clause for a boolean query, adding its parameters to
+/// `values`. Every word or phrase matches when it's in any of the columns
+/// of `field`.
+fn query_sql(
+    query: &Query,
+    field: SearchField,
+    case_sensitive: bool,
+    values: &mut Vec<Value>,
+) -> String {
+    let pattern = match query {
+        Query::Word(word) => glob_escape(word),
+        // `*` is taken literally as well
+        Query::Phrase(phrase) => glob_escape(phrase).replace('*', "[*]"),
+        _ => String::new(),
+    };
+    match query {
+        Query::Word(_) | Query::Phrase(_) => {
+            let pattern = match case_sensitive {
+                true => pattern,
+                false => fold_case(&pattern),
+            };
+            values.push(format!("*{}*", pattern).into());
+            let n = values.len();
+            let columns = field
+                .fields()
+                .iter()
+                .map(|f| match case_sensitive {
+                    true => format!("{{t}}{} glob ?{}", f.column(), n),
+                    false => format!("casefold({{t}}{}) glob ?{}", f.column(), n),
+                })
+                .join(" or ");
+            format!("({})", columns)
         }
+        Query::Not(query) => format!("not {}", query_sql(query, field, case_sensitive, values)),
+        Query::And(queries) if queries.is_empty() => "1".to_string(),
+        Query::And(queries) => format!(
+            "({})",
+            queries
+                .iter()
+                .map(|q| query_sql(q, field, case_sensitive, values))
+                .join(" and ")
+        ),
+        Query::Or(queries) => format!(
+            "({})",
+            queries
+                .iter()
+                .map(|q| query_sql(q, field, case_sensitive, values))
+                .join(" or ")
+        ),
+    }
+}
 
-        Ok(tx.commit()?)
+/// The ways a cwd under directory `path` can be written: `path` with `.`,
+/// `..`, repeated and trailing separators resolved, relative to the current
+/// directory if it's relative, and where that's different, the same with
+/// symlinks resolved
+///
+/// Only `path` is resolved, not the stored cwds, so a command run in
+/// `/link/src`, with `/link` a symlink to `/real`, is under `/link` but not
+/// under `/real`. A relative `path` means something else in every directory
+/// it's used from, and `..` is resolved before symlinks, like `cd` does.
+pub fn cwd_forms(path: &str) -> Vec<String> {
+    let mut dir = PathBuf::new();
+    if Path::new(path).is_relative() {
+        if let Ok(current) = std::env::current_dir() {
+            dir = current;
+        }
+    }
+    for component in Path::new(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                dir.pop();
+            }
+            c => dir.push(c),
+        }
+    }
+
+    let mut forms = vec![dir.to_string_lossy().to_string()];
+    if let Ok(real) = std::fs::canonicalize(&dir) {
+        let real = real.to_string_lossy().to_string();
+        if !forms.contains(&real) {
+            forms.push(real);
+        }
+    }
+    forms
+}
+
+/// `dir` without trailing separators, unless it's only separators
+fn trim_separators(dir: &str) -> &str {
+    match dir.trim_end_matches(std::path::is_separator) {
+        "" if !dir.is_empty() => &dir[..1],
+        trimmed => trimmed,
+    }
+}
+
+/// A condition for `column` being a directory under one of `dirs`, or one of
+/// them itself, e.g. for every one of the `cwd_forms` of a path. The range
+/// comparisons let it use the index on `cwd`. Parameters are added to
+/// `values`, numbered after `offset` others.
+fn cwd_under_sql(column: &str, dirs: &[String], offset: usize, values: &mut Vec<Value>) -> String {
+    let clauses = dirs
+        .iter()
+        .map(|dir| {
+            // everything under `dir/` sorts before `dir` followed by the
+            // character after the separator
+            let below = match dir.ends_with(std::path::is_separator) {
+                true => dir.clone(),
+                false => format!("{}{}", dir, std::path::MAIN_SEPARATOR),
+            };
+            let mut above = below.clone();
+            let separator = above.pop().unwrap();
+            above.push((separator as u8 + 1) as char);
+
+            let n = offset + values.len() + 1;
+            values.push(dir.clone().into());
+            values.push(below.into());
+            values.push(above.into());
+            format!(
+                "{c} = ?{} or ({c} >= ?{} and {c} < ?{})",
+                n,
+                n + 1,
+                n + 2,
+                c = column
+            )
+        })
+        .join(" or ");
+    format!("({})", clauses)
+}
+
+/// `history_items h` with only the newest row of every `key` among the
+/// rows matching `wanted`, the one with the highest `history_id` when
+/// several ran at the same time, found with one pass over the rows rather
+/// than one per row. `stats` is a list of columns starting with a comma,
+/// aggregated over the window `same_key` of the rows with that key.
+fn newest_rows_sql(key: UniqueKey, wanted: &str, stats: &str) -> String {
+    format!(
+        "(select * from (
+                select *, row_number() over newest as newest_rank{}
+                from history_items where {}
+                window same_key as (partition by {}),
+                newest as (same_key order by timestamp desc, history_id desc)
+            ) where newest_rank = 1) h",
+        stats,
+        wanted,
+        key.columns().join(", ")
+    )
+}
+
+/// The smallest string greater than every string starting with `prefix`,
+/// `None` for an empty prefix, which every string starts with
+fn prefix_upper_bound(prefix: &str) -> Option<String> {
+    let mut upper = prefix.to_string();
+    while let Some(last) = upper.pop() {
+        let next = match last {
+            '\u{d7ff}' => Some('\u{e000}'),
+            c => char::from_u32(c as u32 + 1),
+        };
+        if let Some(next) = next {
+            upper.push(next);
+            return Some(upper);
+        }
+    }
+    None
+}
+
+/// Turn a query, where `*` matches anything, into a `glob` pattern that
+/// takes every other character literally
+fn glob_escape(query: &str) -> String {
+    query
+        .chars()
+        .map(|c| match c {
+            '?' | '[' => format!("[{}]", c),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Finds where a search query matched in the text of a hit
+enum Matcher {
+    /// Prefix and fulltext queries are turned into a regex as well. When
+    /// `folded`, it runs on case folded text.
+    Regex {
+        regex: Regex,
+        folded: bool,
+    },
+    Fuzzy {
+        query: String,
+        case_sensitive: bool,
+    },
+    /// The query case folded, with its trigrams
+    Typo {
+        query: Vec<char>,
+        trigrams: HashSet<String>,
+    },
+}
+
+impl Matcher {
+    fn new(query: &str, mode: SearchMode, case_sensitive: bool) -> Result<Self> {
+        let glob = |anchor: &str| -> Result<Self> {
+            let query = if case_sensitive {
+                query.to_string()
+            } else {
+                fold_case(query)
+            };
+            let pattern = query.split('*').map(regex::escape).join(".*?");
+            Ok(Matcher::Regex {
+                regex: Regex::new(&format!("(?s){}{}", anchor, pattern))?,
+                folded: !case_sensitive,
+            })
+        };
+        match mode {
+            SearchMode::Prefix => glob("^"),
+            SearchMode::FullText => glob(""),
+            SearchMode::Regex => {
+                let pattern = if case_sensitive {
+                    query.to_string()
+                } else {
+                    format!("(?i){}", query)
+                };
+                Ok(Matcher::Regex {
+                    regex: Regex::new(&pattern)?,
+                    folded: false,
+                })
+            }
+            SearchMode::Fuzzy => Ok(Matcher::Fuzzy {
+                query: query.to_string(),
+                case_sensitive,
+            }),
+            SearchMode::Boolean => Self::for_query(&Query::parse(query)?, case_sensitive),
+            SearchMode::Typo => {
+                let query = fold_case(query);
+                Ok(Matcher::Typo {
+                    trigrams: trigrams(&query),
+                    query: query.chars().collect(),
+                })
+            }
+        }
+    }
+
+    /// Highlights the words and phrases of `query` that aren't excluded
+    fn for_query(query: &Query, case_sensitive: bool) -> Result<Self> {
+        let fold = |text: &str| match case_sensitive {
+            true => text.to_string(),
+            false => fold_case(text),
+        };
+        let pattern = query
+            .positive()
+            .into_iter()
+            .map(|term| match term {
+                Query::Word(word) => fold(word).split('*').map(regex::escape).join(".*?"),
+                Query::Phrase(phrase) => regex::escape(&fold(phrase)),
+                _ => unreachable!(),
+            })
+            .join("|");
+        Ok(Matcher::Regex {
+            regex: Regex::new(&format!("(?s){}", pattern))?,
+            folded: !case_sensitive,
+        })
+    }
+
+    /// How good a match with the score `find` gave is, between 0 and 1, so
+    /// that it can weigh a ranking score. 1 for the unscored modes.
+    fn quality(&self, score: i64) -> f64 {
+        match self {
+            Matcher::Regex { .. } => 1.0,
+            // the query matching itself is about as good as it gets
+            Matcher::Fuzzy {
+                query,
+                case_sensitive,
+            } => {
+                let best = fuzzy_match_with_case(query, query, *case_sensitive)
+                    .map_or(1, |m| m.score.max(1));
+                (score.max(1) as f64 / best as f64).min(1.0)
+            }
+            Matcher::Typo { query, .. } => quality(score, max_typos(query.len())),
+        }
+    }
+
+    /// The fuzzy score, 0 for the other modes, and the byte ranges of every
+    /// match, or `None` when the query doesn't match `text`
+    fn find(&self, text: &str) -> Option<(i64, Vec<Range<usize>>)> {
+        match self {
+            Matcher::Regex {
+                regex,
+                folded: false,
+            } => {
+                let found = regex.find_iter(text).map(|m| m.range()).collect_vec();
+                match found.is_empty() {
+                    true => None,
+                    false => Some((0, found.into_iter().filter(|r| !r.is_empty()).collect())),
+                }
+            }
+            Matcher::Regex {
+                regex,
+                folded: true,
+            } => {
+                let (folded, origins) = fold_case_with_origins(text);
+                let found = regex.find_iter(&folded).map(|m| m.range()).collect_vec();
+                match found.is_empty() {
+                    true => None,
+                    false => Some((
+                        0,
+                        // matches in the folded text can share a character
+                        // of `text`, like the two in `ss` from `ß`
+                        merge_ranges(
+                            found
+                                .into_iter()
+                                .filter(|r| !r.is_empty())
+                                .map(|r| origins[r.start].start..origins[r.end - 1].end),
+                        ),
+                    )),
+                }
+            }
+            Matcher::Fuzzy {
+                query,
+                case_sensitive,
+            } => {
+                let m = fuzzy_match_with_case(query, text, *case_sensitive)?;
+                // one range per run of adjacent characters
+                let mut matches: Vec<Range<usize>> = vec![];
+                for position in m.positions {
+                    let end = position + text[position..].chars().next().map_or(0, char::len_utf8);
+                    match matches.last_mut() {
+                        Some(last) if last.end == position => last.end = end,
+                        _ => matches.push(position..end),
+                    }
+                }
+                Some((m.score, matches))
+            }
+            Matcher::Typo {
+                query,
+                trigrams: query_trigrams,
+            } => {
+                let mut folded = String::with_capacity(text.len());
+                let mut origins = Vec::with_capacity(text.len());
+                for (start, c) in text.char_indices() {
+                    let from = folded.len();
+                    fold_char(c, &mut folded);
+                    for _ in folded[from..].chars() {
+                        origins.push(start..start + c.len_utf8());
+                    }
+                }
+                let chars = folded.chars().collect_vec();
+                let m = typo_match(query, &chars, max_typos(query.len()))?;
+                let score = m.score(similarity(query_trigrams, &trigrams(&folded)));
+                let matches = (m.start < m.end)
+                    .then(|| origins[m.start].start..origins[m.end - 1].end)
+                    .into_iter()
+                    .collect();
+                Some((score, matches))
+            }
+        }
+    }
+}
+
+/// `ranges`, which are in order of their start, with overlapping ones merged
+fn merge_ranges(ranges: impl IntoIterator<Item = Range<usize>>) -> Vec<Range<usize>> {
+    let mut merged: Vec<Range<usize>> = vec![];
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start < last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Append `c` case folded, see `fold_case`
+pub fn fold_char(c: char, folded: &mut String) {
+    for c in c.to_lowercase() {
+        match c {
+            'ß' => folded.push_str("ss"),
+            'ς' => folded.push('σ'),
+            c => folded.push(c),
+        }
+    }
+}
+
+/// `fold_case`, with the range in `text` of the character every byte of
+/// the folded text came from
+fn fold_case_with_origins(text: &str) -> (String, Vec<Range<usize>>) {
+    let mut folded = String::with_capacity(text.len());
+    let mut origins = Vec::with_capacity(text.len());
+    for (start, c) in text.char_indices() {
+        fold_char(c, &mut folded);
+        origins.resize(folded.len(), start..start + c.len_utf8());
+    }
+    (folded, origins)
+}
+
+/// Full Unicode case folding, so that e.g. `STRASSE`, `straße` and
+/// `Straße` compare equal once folded. Sigma folds to `σ` wherever it is
+/// in a word.
+pub fn fold_case(text: &str) -> String {
+    let mut folded = String::with_capacity(text.len());
+    for c in text.chars() {
+        fold_char(c, &mut folded);
+    }
+    folded
+}
+
+/// Register `casefold(text)`, `fold_case` for queries
+fn add_casefold_function(conn: &Connection) -> rusqlite::Result<()> {
+    conn.create_scalar_function(
+        "casefold",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            Ok(match ctx.get_raw(0) {
+                ValueRef::Text(text) => Some(fold_case(&String::from_utf8_lossy(text))),
+                _ => None,
+            })
+        },
+    )
+}
+
+/// Register `regexp(pattern, text)` so that `text REGEXP pattern` works in
+/// queries. The pattern is compiled once per statement, not once per row.
+fn add_regexp_function(conn: &Connection) -> rusqlite::Result<()> {
+    conn.create_scalar_function(
+        "regexp",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let re: Arc<Regex> = ctx.get_or_create_aux(0, |pattern| -> Result<Regex> {
+                Ok(Regex::new(pattern.as_str()?)?)
+            })?;
+            Ok(match ctx.get_raw(1) {
+                ValueRef::Text(text) => re.is_match(&String::from_utf8_lossy(text)),
+                _ => false,
+            })
+        },
+    )
+}
+
+impl Database for Sqlite {
+    fn save(&mut self, h: &HistoryItem) -> Result<()> {
+        debug!("saving history to sqlite");
+        debug!("HistoryItem: {:#?}", &h);
+        self.in_scope(true, |tx| Self::save_raw(tx, h))?;
+        Ok(())
+    }
+
+    fn save_bulk(&mut self, h: &[HistoryItem]) -> Result<()> {
+        debug!("saving history to sqlite");
+
+        self.in_scope(true, |tx| {
+            for i in h {
+                Self::save_raw(tx, i)?;
+            }
+            Ok(())
+        })
     }
 
     fn load(&self, id: &str) -> Result<HistoryItem> {
@@ -274,8 +1807,26 @@ impl Database for Sqlite {
         let mut stmt = self
             .conn
             .prepare("select * from history_items where history_id = ?1")?;
-        let row = stmt.query_row(params![id], |r| Ok(Self::query_history(r)))?;
-        row
+        let mut item = stmt.query_row(params![id], |r| Ok(Self::query_history(r)))??;
+        if let Some(history_id) = item.history_id {
+            item.metadata = self.metadata(history_id)?;
+        }
+        Ok(item)
+    }
+
+    fn metadata(&self, history_id: i64) -> Result<BTreeMap<String, Vec<String>>> {
+        let mut metadata: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        if !Self::has_table(&self.conn, "history_metadata")? {
+            return Ok(metadata);
+        }
+        let mut stmt = self.conn.prepare_cached(
+            "select key, value from history_metadata where history_id = ?1 order by rowid",
+        )?;
+        let mut rows = stmt.query(params![history_id])?;
+        while let Some(row) = rows.next()? {
+            metadata.entry(row.get(0)?).or_default().push(row.get(1)?);
+        }
+        Ok(metadata)
     }
 
     fn update(&self, h: &HistoryItem) -> Result<usize> {
@@ -283,11 +1834,11 @@ impl Database for Sqlite {
         debug!("history_item = [{:#?}]", &h);
 
         let cmd_params = match h.command_params.as_ref() {
-            Some(p) => &p,
+            Some(p) => p,
             None => "",
         };
 
-        Ok(self.conn.execute(
+        let updated = self.conn.execute(
             "update history_items
             set command_line = ?1, command = ?2, command_params = ?3, cwd = ?4, duration = ?5,
             exit_status = ?6, session_id = ?7, timestamp = ?8, run_count = ?9 where history_id = ?10",
@@ -303,7 +1854,12 @@ impl Database for Sqlite {
                 h.run_count,
                 h.history_id
             ],
-        )?)
+        )?;
+        self.deliver_committed();
+        if updated > 0 {
+            Self::index_trigrams(&self.conn, &h.command_line)?;
+        }
+        Ok(updated)
     }
 
     // make a unique list, that only shows the *newest* version of things
@@ -314,20 +1870,14 @@ impl Database for Sqlite {
         // however, this is client side, and only used by the client, on their
         // own data. They can just open the db file...
         // otherwise building the query is awkward
+        if unique {
+            return self.list_unique(max, UniqueKey::Command);
+        }
+
         let query = format!(
             "select * from history_items h
-                {}
                 order by timestamp desc
                 {}",
-            // inject the unique check
-            if unique {
-                "where timestamp = (
-                        select max(timestamp) from history_items
-                        where h.command = history_items.command
-                    )"
-            } else {
-                ""
-            },
             // inject the limit
             if let Some(max) = max {
                 format!("limit {}", max)
@@ -337,10 +1887,10 @@ impl Database for Sqlite {
         );
 
         let mut hist_rows: Vec<HistoryItem> = Vec::new();
-        let mut stmt = self.conn.prepare(query.as_str())?;
+        let mut stmt = self.conn.prepare(self.with_attached(&query).as_str())?;
         // debug!("SQL: {}", stmt.expanded_sql().unwrap());
 
-        let rows = stmt.query_and_then([], |row| Self::query_history(row))?;
+        let rows = stmt.query_and_then([], Self::query_history)?;
         for row in rows {
             hist_rows.push(row?);
         }
@@ -348,6 +1898,26 @@ impl Database for Sqlite {
         Ok(hist_rows)
     }
 
+    fn list_unique(&self, max: Option<usize>, key: UniqueKey) -> Result<Vec<HistoryItem>> {
+        debug!("listing history unique by {}", key);
+
+        let query = format!(
+            "select h.* from {}
+                order by h.timestamp desc
+                {}",
+            newest_rows_sql(key, "1", ""),
+            match max {
+                Some(max) => format!("limit {}", max),
+                None => "".to_string(),
+            }
+        );
+
+        let mut stmt = self.conn.prepare(self.with_attached(&query).as_str())?;
+        let rows = stmt.query_and_then([], Self::query_history)?;
+
+        rows.collect()
+    }
+
     fn range(
         &self,
         from: chrono::DateTime<Utc>,
@@ -357,7 +1927,9 @@ impl Database for Sqlite {
 
         let mut hist_rows: Vec<HistoryItem> = Vec::new();
 
-        let mut stmt = self.conn.prepare("select * from history_items where timestamp >= ?1 and timestamp <= ?2 order by timestamp asc")?;
+        let mut stmt = self.conn.prepare(
+            self.with_attached("select * from history_items where timestamp >= ?1 and timestamp <= ?2 order by timestamp asc").as_str(),
+        )?;
 
         let rows = stmt
             .query_and_then([&from.timestamp_nanos(), &to.timestamp_nanos()], |row| {
@@ -371,7 +1943,10 @@ impl Database for Sqlite {
     }
 
     fn history_count(&self) -> Result<i64> {
-        let mut stmt = self.conn.prepare("select count(1) from history_items")?;
+        let mut stmt = self.conn.prepare(
+            self.with_attached("select count(1) from history_items")
+                .as_str(),
+        )?;
 
         let cnt = stmt.query_row([], |r| r.get(0))?;
         Ok(cnt)
@@ -382,32 +1957,35 @@ impl Database for Sqlite {
         //     "select * from history_items where duration >= 0 order by timestamp asc limit 1",
         // )?;
 
-        let mut stmt = self
-            .conn
-            .prepare("select * from history_items order by timestamp asc limit 1")?;
+        let mut stmt = self.conn.prepare(
+            self.with_attached("select * from history_items order by timestamp asc limit 1")
+                .as_str(),
+        )?;
 
-        let row = stmt.query_row([], |r| Ok(Self::query_history(r)))?;
-        row
+        stmt.query_row([], |r| Ok(Self::query_history(r)))?
     }
 
     fn last(&self) -> Result<HistoryItem> {
         // let mut stmt = self.conn.prepare(
         //     "select * from history_items where duration >= 0 order by timestamp desc limit 1",
         // )?;
-        let mut stmt = self
-            .conn
-            .prepare("select * from history_items order by timestamp desc limit 1")?;
+        let mut stmt = self.conn.prepare(
+            self.with_attached("select * from history_items order by timestamp desc limit 1")
+                .as_str(),
+        )?;
 
         // debug!("sql: {}", stmt.expanded_sql().unwrap());
-        let row = stmt.query_row([], |r| Ok(Self::query_history(r)))?;
-        row
+        stmt.query_row([], |r| Ok(Self::query_history(r)))?
     }
 
     fn before(&self, timestamp: chrono::DateTime<Utc>, count: i64) -> Result<Vec<HistoryItem>> {
         let mut hist_rows: Vec<HistoryItem> = Vec::new();
 
         let mut stmt = self.conn.prepare(
-            "select * from history_items where timestamp < ?1 order by timestamp desc limit ?2",
+            self.with_attached(
+                "select * from history_items where timestamp < ?1 order by timestamp desc limit ?2",
+            )
+            .as_str(),
         )?;
 
         let rows = stmt.query_and_then([timestamp.timestamp_nanos(), count], |row| {
@@ -425,44 +2003,215 @@ impl Database for Sqlite {
         limit: Option<i64>,
         search_mode: SearchMode,
         query: &str,
-    ) -> Result<Vec<HistoryItem>> {
+    ) -> Result<Vec<SearchHit>> {
         debug!("starting search");
-        let query = query.to_string().replace("*", "%"); // allow wildcard char
-        let limit = limit.map_or("".to_owned(), |l| format!("limit {}", l));
+        let options = SearchOptions {
+            limit,
+            mode: search_mode,
+            ..Default::default()
+        };
+
+        self.search_ranked(query, &options)
+    }
+
+    fn search_ranked(&self, query: &str, options: &SearchOptions) -> Result<Vec<SearchHit>> {
+        debug!("starting ranked search with {:?}", options);
+        if options.mode == SearchMode::Boolean {
+            return self.search_query(&Query::parse(query)?, options);
+        }
+        if options.mode == SearchMode::Typo {
+            return self.search_typo(query, options);
+        }
 
-        let query = match search_mode {
-            SearchMode::Prefix => query,
-            SearchMode::FullText => format!("%{}", query),
-            SearchMode::Fuzzy => query.split("").join("%"),
+        let case_sensitive = options.case.is_sensitive(query, options.mode);
+        let fold = |pattern: String| {
+            if case_sensitive {
+                pattern
+            } else {
+                fold_case(&pattern)
+            }
+        };
+        // `glob` is case sensitive and `like` only folds ascii, so both
+        // compare case folded text unless the search is case sensitive. A
+        // regex folds case itself. Folding the column keeps sqlite from
+        // using an index on it, so a case insensitive prefix search reads
+        // every row where a case sensitive one is a range of
+        // `idx_history_command`.
+        let column = if case_sensitive || options.mode == SearchMode::Regex {
+            "{t}{}"
+        } else {
+            "casefold({t}{})"
         };
+        let (param, matches) = match options.mode {
+            // allow wildcard char
+            SearchMode::Prefix => (fold(format!("{}*", glob_escape(query))), "{} glob ?1"),
+            SearchMode::FullText => (fold(format!("*{}*", glob_escape(query))), "{} glob ?1"),
+            SearchMode::Regex => {
+                let pattern = if case_sensitive {
+                    query.to_string()
+                } else {
+                    format!("(?i){}", query)
+                };
+                // report a bad pattern here rather than as a failed query
+                if let Err(e) = Regex::new(&pattern) {
+                    bail!("invalid regular expression {:?}: {}", query, e);
+                }
+                (pattern, "{} regexp ?1")
+            }
+            SearchMode::Fuzzy => (
+                fuzzy_prefilter(&fold(query.to_string())),
+                "{} like '%' || ?1 || '%' escape '\\'",
+            ),
+            SearchMode::Boolean => unreachable!("boolean queries go through search_query"),
+            SearchMode::Typo => unreachable!("typo queries go through search_typo"),
+        };
+        let matches = matches.replace("{}", column);
+        let matcher = Matcher::new(query, options.mode, case_sensitive)?;
+        let matches = format!(
+            "({})",
+            options
+                .field
+                .fields()
+                .iter()
+                .map(|f| matches.replace("{}", f.column()))
+                .join(" or ")
+        );
 
-        let mut hist_rows: Vec<HistoryItem> = Vec::new();
-        let mut stmt = self.prepare(
-            format!(
-                "select * from history_items h
-            where command like ?1 || '%'
-            and timestamp = (
-                    select max(timestamp) from history_items
-                    where h.command = history_items.command
-                )
-            order by timestamp desc {}",
-                limit.clone()
-            )
-            .as_str(),
-        )?;
-        let rows = stmt.query_and_then([&query], |row| Self::query_history(row))?;
-        for row in rows {
-            hist_rows.push(row?);
+        self.search_with(vec![param.into()], matches, matcher, options)
+    }
+
+    fn search_query(&self, query: &Query, options: &SearchOptions) -> Result<Vec<SearchHit>> {
+        debug!("starting boolean search for {:?} with {:?}", query, options);
+
+        // keywords like OR and NOT don't make a smart case search sensitive
+        let case_sensitive = options
+            .case
+            .is_sensitive(&query.terms().join(" "), SearchMode::FullText);
+        let mut values: Vec<Value> = vec![];
+        let matches = query_sql(query, options.field, case_sensitive, &mut values);
+        let matcher = Matcher::for_query(query, case_sensitive)?;
+
+        self.search_with(values, matches, matcher, options)
+    }
+
+    fn suggest(
+        &self,
+        prefix: &str,
+        context: &SearchContext,
+        weights: &ContextWeights,
+    ) -> Result<Option<String>> {
+        let upper = match prefix_upper_bound(prefix) {
+            Some(upper) => upper,
+            None => return Ok(None),
+        };
+
+        // a prefix with few runs is ranked on all of them, read from
+        // idx_history_suggest alone. One with more, as a short prefix has, is
+        // ranked on its newest runs, walking idx_history_timestamp back from
+        // now, so that no keystroke reads the whole history. Each database
+        // is read on its own so that attached ones keep their indexes.
+        let mut candidates: Vec<String> = Vec::new();
+        for schema in std::iter::once("main").chain(self.attached.iter().map(|a| a.as_str())) {
+            let runs: usize = self
+                .prepare_cached(&format!(
+                    "select count(1) from (select 1 from \"{}\".history_items
+                    where command_line >= ?1 and command_line < ?2 limit {})",
+                    schema,
+                    SUGGEST_CANDIDATES + 1
+                ))?
+                .query_row(params![prefix, upper], |r| r.get(0))?;
+            candidates.push(match runs > SUGGEST_CANDIDATES {
+                false => format!(
+                    "select command_line, timestamp, exit_status, cwd, session_id, run_count
+                    from \"{}\".history_items where command_line >= ?1 and command_line < ?2",
+                    schema
+                ),
+                // `+` keeps the range from being looked up in an index
+                true => format!(
+                    "select * from (
+                        select command_line, timestamp, exit_status, cwd, session_id, run_count
+                        from \"{}\".history_items
+                        where +command_line >= ?1 and +command_line < ?2
+                        order by timestamp desc limit {}
+                    )",
+                    schema, SUGGEST_CANDIDATES
+                ),
+            });
         }
+        let mut stmt = self.prepare_cached(&format!(
+            "select command_line, count(1) as executions, sum(run_count) as total_runs,
+            max(timestamp) as latest, total(exit_status = 0) as successes,
+            total(cwd = ?3) as cwd_runs, total(session_id = ?4) as session_runs
+            from ({})
+            group by command_line",
+            candidates.join(" union all ")
+        ))?;
 
-        Ok(hist_rows)
+        let now = Utc::now();
+        let mut best: Option<(f64, String)> = None;
+        let mut rows = stmt.query(params![prefix, upper, context.cwd, context.session_id])?;
+        while let Some(row) = rows.next()? {
+            let executions: i64 = row.get("executions")?;
+            let age = now - Utc.timestamp_nanos(row.get("latest")?);
+            // commands that usually fail make poor suggestions
+            let success_rate = (row.get::<_, f64>("successes")? + 1.0) / (executions as f64 + 1.0);
+            let score = (weights
+                .frecency
+                .score(row.get("total_runs")?, executions, age)
+                + weights.cwd * row.get::<_, f64>("cwd_runs")?
+                + weights.session * row.get::<_, f64>("session_runs")?)
+                * success_rate;
+            match &best {
+                Some((b, _)) if score <= *b => {}
+                _ => best = Some((score, row.get("command_line")?)),
+            }
+        }
+
+        Ok(best.map(|(_, command_line)| command_line))
+    }
+
+    fn predict_next(&self, last_command_line: &str) -> Result<Vec<Prediction>> {
+        debug!("predicting the command line after {:?}", last_command_line);
+
+        // a database opened read only may be from before the transitions
+        // were kept, so they are counted on the spot
+        let transitions = match Self::has_table(&self.conn, "command_line_transitions")? {
+            true => "command_line_transitions".to_string(),
+            false => format!("({})", COUNT_TRANSITIONS),
+        };
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "select next, count, total(count) over () as total from {}
+            where previous = ?1 order by count desc, last_seen desc",
+            transitions
+        ))?;
+        let rows = stmt.query_map([last_command_line], |row| {
+            Ok(Prediction {
+                command_line: row.get("next")?,
+                count: row.get("count")?,
+                probability: row.get::<_, f64>("count")? / row.get::<_, f64>("total")?,
+            })
+        })?;
+
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn list_ranked(&self, max: Option<usize>, ranking: &Ranking) -> Result<Vec<SearchHit>> {
+        debug!("listing history ranked by {:?}", ranking);
+        let options = SearchOptions {
+            limit: max.map(|m| m as i64),
+            mode: SearchMode::Prefix,
+            ranking: ranking.clone(),
+            ..Default::default()
+        };
+
+        self.search_ranked("", &options)
     }
 
     fn query_history(&self, query: &str) -> Result<Vec<HistoryItem>> {
         let mut hist_rows: Vec<HistoryItem> = Vec::new();
         let mut stmt = self.conn.prepare(query)?;
 
-        let rows = stmt.query_and_then([], |row| Self::query_history(row))?;
+        let rows = stmt.query_and_then([], Self::query_history)?;
         for row in rows {
             hist_rows.push(row?);
         }
@@ -475,111 +2224,2290 @@ impl Database for Sqlite {
             .conn
             .prepare("delete from history_items where history_id = ?1")?;
         stmt.execute(params![id])?;
+        self.deliver_committed();
         Ok(self.conn.last_insert_rowid())
         // Ok(self.conn.changes())
     }
-}
 
-#[derive(Clone, Debug, Copy)]
-pub enum SearchMode {
-    // #[serde(rename = "prefix")]
-    Prefix,
-
-    // #[serde(rename = "fulltext")]
-    FullText,
+    fn select_where(&self, filter: &HistoryFilter) -> Result<Vec<HistoryItem>> {
+        debug!("selecting history where {:?}", filter);
+        let (clause, values) = filter.to_sql(0);
 
-    // #[serde(rename = "fuzzy")]
-    Fuzzy,
-}
+        let mut hist_rows: Vec<HistoryItem> = Vec::new();
+        let mut stmt = self.conn.prepare(
+            format!(
+                "select * from history_items where {} order by timestamp desc",
+                clause
+            )
+            .as_str(),
+        )?;
+        let rows = stmt.query_and_then(params_from_iter(values), Self::query_history)?;
+        for row in rows {
+            hist_rows.push(row?);
+        }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+        Ok(hist_rows)
+    }
 
-    fn new_history_item(db: &mut impl Database, cmd: &str) -> Result<()> {
-        let history = HistoryItem::new(
-            chrono::Local::now(),
-            cmd.to_string(),
-            "/home/ellie".to_string(),
-            0,
-            1,
-            Some("beep boop".to_string()),
-            Some("booop".to_string()),
+    fn select_update(
+        &self,
+        filter: &HistoryFilter,
+        changes: &HistoryUpdate,
+    ) -> Result<Vec<HistoryItem>> {
+        debug!(
+            "selecting history to update where {:?} with {:?}",
+            filter, changes
         );
-        return db.save(&history).await;
-    }
+        let (_, clause, values) = update_sql(filter, changes)?;
 
-    #[tokio::test(flavor = "multi_thread")]
-    fn test_search_prefix() {
-        let mut db = Sqlite::new("sqlite::memory:", SqlLogMode::Disabled)
-            .await
-            .unwrap();
-        new_history_item(&mut db, "ls /home/ellie").await.unwrap();
+        let mut hist_rows: Vec<HistoryItem> = Vec::new();
+        let mut stmt = self.conn.prepare(
+            format!(
+                "select * from history_items where {} order by timestamp desc",
+                clause
+            )
+            .as_str(),
+        )?;
+        let rows = stmt.query_and_then(params_from_iter(values), Self::query_history)?;
+        for row in rows {
+            hist_rows.push(row?);
+        }
 
-        let mut results = db.search(None, SearchMode::Prefix, "ls").await.unwrap();
-        assert_eq!(results.len(), 1);
+        Ok(hist_rows)
+    }
 
-        results = db.search(None, SearchMode::Prefix, "/home").await.unwrap();
-        assert_eq!(results.len(), 0);
+    fn update_where(
+        &self,
+        filter: &HistoryFilter,
+        changes: &HistoryUpdate,
+        dry_run: bool,
+    ) -> Result<usize> {
+        debug!("updating history where {:?} with {:?}", filter, changes);
+        let (set, clause, values) = update_sql(filter, changes)?;
 
-        results = db.search(None, SearchMode::Prefix, "ls  ").await.unwrap();
-        assert_eq!(results.len(), 0);
+        if dry_run {
+            return Ok(self.conn.query_row(
+                format!("select count(*) from history_items where {}", clause).as_str(),
+                params_from_iter(values),
+                |r| r.get(0),
+            )?);
+        }
+
+        // rows that only collide with each other are still left alone
+        self.in_scope(true, |tx| {
+            let updated = tx.execute(
+                format!(
+                    "update or ignore history_items set {} where {}",
+                    set, clause
+                )
+                .as_str(),
+                params_from_iter(values),
+            )?;
+            if let (true, Some(command_line)) = (updated > 0, &changes.command_line) {
+                Self::index_trigrams(tx, command_line)?;
+            }
+            Ok(updated)
+        })
     }
 
-    #[tokio::test(flavor = "multi_thread")]
-    fn test_search_fulltext() {
-        let mut db = Sqlite::new("sqlite::memory:", SqlLogMode::Disabled)
-            .await
-            .unwrap();
-        new_history_item(&mut db, "ls /home/ellie").await.unwrap();
+    fn delete_where(&self, filter: &HistoryFilter, dry_run: bool) -> Result<usize> {
+        debug!("deleting history where {:?}", filter);
+        if filter.is_empty() {
+            bail!("refusing to delete every history item, the filter is empty");
+        }
+        let (clause, values) = filter.to_sql(0);
 
-        let mut results = db.search(None, SearchMode::FullText, "ls").await.unwrap();
-        assert_eq!(results.len(), 1);
+        if dry_run {
+            return Ok(self.conn.query_row(
+                format!("select count(*) from history_items where {}", clause).as_str(),
+                params_from_iter(values),
+                |r| r.get(0),
+            )?);
+        }
 
-        results = db
-            .search(None, SearchMode::FullText, "/home")
-            .await
-            .unwrap();
-        assert_eq!(results.len(), 1);
+        self.in_scope(true, |tx| {
+            Ok(tx.execute(
+                format!("delete from history_items where {}", clause).as_str(),
+                params_from_iter(values),
+            )?)
+        })
+    }
 
-        results = db.search(None, SearchMode::FullText, "ls  ").await.unwrap();
-        assert_eq!(results.len(), 0);
+    fn transaction<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        let scope = self.begin_scope()?;
+        match f(self) {
+            Ok(v) => {
+                self.end_scope(scope, true)?;
+                Ok(v)
+            }
+            Err(e) => {
+                let _ = self.end_scope(scope, false);
+                Err(e)
+            }
+        }
     }
+}
 
-    #[tokio::test(flavor = "multi_thread")]
-    fn test_search_fuzzy() {
-        let mut db = Sqlite::new("sqlite::memory:", SqlLogMode::Disabled)
-            .await
-            .unwrap();
-        new_history_item(&mut db, "ls /home/ellie").await.unwrap();
-        new_history_item(&mut db, "ls /home/frank").await.unwrap();
-        new_history_item(&mut db, "cd /home/ellie").await.unwrap();
-        new_history_item(&mut db, "/home/ellie/.bin/rustup")
-            .await
-            .unwrap();
+#[derive(Clone, Debug, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum SearchMode {
+    #[default]
+    #[serde(rename = "prefix")]
+    Prefix,
 
-        let mut results = db.search(None, SearchMode::Fuzzy, "ls /").await.unwrap();
-        assert_eq!(results.len(), 2);
+    #[serde(rename = "fulltext")]
+    FullText,
 
-        results = db.search(None, SearchMode::Fuzzy, "l/h/").await.unwrap();
-        assert_eq!(results.len(), 2);
+    #[serde(rename = "fuzzy")]
+    Fuzzy,
 
-        results = db.search(None, SearchMode::Fuzzy, "/h/e").await.unwrap();
-        assert_eq!(results.len(), 3);
+    #[serde(rename = "regex")]
+    Regex,
 
-        results = db.search(None, SearchMode::Fuzzy, "/hmoe/").await.unwrap();
-        assert_eq!(results.len(), 0);
+    /// The query is parsed as a `Query`
+    #[serde(rename = "boolean")]
+    Boolean,
 
-        results = db
-            .search(None, SearchMode::Fuzzy, "ellie/home")
-            .await
-            .unwrap();
-        assert_eq!(results.len(), 0);
+    /// Command lines a few typos away from the query, found through
+    /// `command_trigrams`. Always case insensitive.
+    #[serde(rename = "typo")]
+    Typo,
+}
 
-        results = db.search(None, SearchMode::Fuzzy, "lsellie").await.unwrap();
-        assert_eq!(results.len(), 1);
+impl SearchMode {
+    /// Whether the mode scores how well every hit matched, so that the
+    /// best match comes first with the recency ranking
+    pub fn is_scored(&self) -> bool {
+        matches!(self, SearchMode::Fuzzy | SearchMode::Typo)
+    }
+}
 
-        results = db.search(None, SearchMode::Fuzzy, " ").await.unwrap();
-        assert_eq!(results.len(), 3);
+/// Whether a search tells upper and lower case apart. Without case
+/// sensitivity, text is compared fully case folded with `fold_case`, so
+/// that `straße` finds `STRASSE`, except in regex searches. Folding a
+/// pattern would change what it means, `\S` to `\s` for one, so those
+/// use the regex crate's `(?i)`, which only folds one character to one:
+/// `ärzte` finds `Ärzte` but `straße` doesn't find `STRASSE`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaseMode {
+    /// Case sensitive only when the query has an upper case letter
+    #[default]
+    Smart,
+    Sensitive,
+    Insensitive,
+}
+
+impl CaseMode {
+    pub fn variants() -> [&'static str; 3] {
+        ["smart", "sensitive", "insensitive"]
+    }
+
+    /// Whether searching for `query` with `mode` is case sensitive
+    pub fn is_sensitive(&self, query: &str, mode: SearchMode) -> bool {
+        match self {
+            CaseMode::Sensitive => true,
+            CaseMode::Insensitive => false,
+            CaseMode::Smart if mode == SearchMode::Regex => {
+                // escapes such as `\S`, `\W` or `\p{Lu}` aren't upper case text
+                let mut chars = query.chars();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            let escaped = chars.next();
+                            if matches!(escaped, Some('p') | Some('P'))
+                                && chars.clone().next() == Some('{')
+                            {
+                                chars.find(|c| *c == '}');
+                            }
+                        }
+                        c if c.is_uppercase() => return true,
+                        _ => {}
+                    }
+                }
+                false
+            }
+            CaseMode::Smart => query.chars().any(char::is_uppercase),
+        }
+    }
+}
+
+impl core::str::FromStr for CaseMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "smart" => Ok(Self::Smart),
+            "sensitive" => Ok(Self::Sensitive),
+            "insensitive" => Ok(Self::Insensitive),
+            _ => Err("Could not parse CaseMode"),
+        }
+    }
+}
+
+impl std::fmt::Display for CaseMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CaseMode::Smart => write!(f, "smart"),
+            CaseMode::Sensitive => write!(f, "sensitive"),
+            CaseMode::Insensitive => write!(f, "insensitive"),
+        }
+    }
+}
+
+/// Which exit statuses a search, list or filter keeps
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExitFilter {
+    #[default]
+    Any,
+    /// Exit status 0
+    Success,
+    /// Any other exit status
+    Failure,
+    /// One of these exit statuses
+    Codes(Vec<i64>),
+}
+
+impl ExitFilter {
+    pub fn variants() -> [&'static str; 3] {
+        ["any", "success", "failure"]
+    }
+
+    pub fn matches(&self, exit_status: i64) -> bool {
+        match self {
+            ExitFilter::Any => true,
+            ExitFilter::Success => exit_status == 0,
+            ExitFilter::Failure => exit_status != 0,
+            ExitFilter::Codes(codes) => codes.contains(&exit_status),
+        }
+    }
+
+    /// A condition on `column`, `None` when every row passes
+    fn to_sql(&self, column: &str) -> Option<String> {
+        match self {
+            ExitFilter::Any => None,
+            ExitFilter::Success => Some(format!("{} = 0", column)),
+            ExitFilter::Failure => Some(format!("{} <> 0", column)),
+            ExitFilter::Codes(codes) => Some(format!(
+                "{} in ({})",
+                column,
+                codes.iter().map(|c| c.to_string()).join(", ")
+            )),
+        }
+    }
+}
+
+impl core::str::FromStr for ExitFilter {
+    type Err = &'static str;
+
+    /// `any`, `success`, `failure` or a comma separated list of exit codes
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(Self::Any),
+            "success" => Ok(Self::Success),
+            "failure" => Ok(Self::Failure),
+            _ => s
+                .split(',')
+                .map(|code| code.trim().parse::<i64>())
+                .collect::<Result<Vec<_>, _>>()
+                .map(Self::Codes)
+                .map_err(|_| "Could not parse ExitFilter"),
+        }
+    }
+}
+
+impl std::fmt::Display for ExitFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitFilter::Any => write!(f, "any"),
+            ExitFilter::Success => write!(f, "success"),
+            ExitFilter::Failure => write!(f, "failure"),
+            ExitFilter::Codes(codes) => write!(f, "{}", codes.iter().join(",")),
+        }
+    }
+}
+
+/// Which columns a search matches the query against
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchField {
+    #[default]
+    Command,
+    CommandLine,
+    Params,
+    Cwd,
+    /// Any of the above
+    Any,
+}
+
+impl SearchField {
+    pub fn variants() -> [&'static str; 5] {
+        ["command", "command_line", "params", "cwd", "any"]
+    }
+
+    /// The single fields this one stands for
+    fn fields(&self) -> &'static [SearchField] {
+        match self {
+            SearchField::Command => &[SearchField::Command],
+            SearchField::CommandLine => &[SearchField::CommandLine],
+            SearchField::Params => &[SearchField::Params],
+            SearchField::Cwd => &[SearchField::Cwd],
+            SearchField::Any => &[
+                SearchField::Command,
+                SearchField::CommandLine,
+                SearchField::Params,
+                SearchField::Cwd,
+            ],
+        }
+    }
+
+    fn column(&self) -> &'static str {
+        match self {
+            SearchField::Command | SearchField::Any => "command",
+            SearchField::CommandLine => "command_line",
+            SearchField::Params => "command_params",
+            SearchField::Cwd => "cwd",
+        }
+    }
+
+    /// This field of `item`, the command for `Any`
+    pub fn text<'a>(&self, item: &'a HistoryItem) -> &'a str {
+        match self {
+            SearchField::Command | SearchField::Any => &item.command,
+            SearchField::CommandLine => &item.command_line,
+            SearchField::Params => item.command_params.as_deref().unwrap_or(""),
+            SearchField::Cwd => &item.cwd,
+        }
+    }
+}
+
+impl core::str::FromStr for SearchField {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "command" => Ok(Self::Command),
+            "command_line" => Ok(Self::CommandLine),
+            "params" => Ok(Self::Params),
+            "cwd" => Ok(Self::Cwd),
+            "any" => Ok(Self::Any),
+            _ => Err("Could not parse SearchField"),
+        }
+    }
+}
+
+/// How search and list results are ordered
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Ranking {
+    /// Newest first, or best match first for fuzzy searches. The score is
+    /// the unix timestamp, or the fuzzy match score.
+    #[default]
+    Recency,
+    /// Often and recently run commands first. In fuzzy and typo searches
+    /// the score is weighed by how well the command matched.
+    Frecency(FrecencyWeights),
+    /// Frecency, boosted for commands that match `SearchOptions::context`,
+    /// and weighed by how well they matched like `Frecency`
+    Context(ContextWeights),
+}
+
+/// Which `Ranking` to use, without its weights, e.g. as picked on the
+/// command line
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RankingKind {
+    #[default]
+    Recency,
+    Frecency,
+    Context,
+}
+
+impl RankingKind {
+    pub fn variants() -> [&'static str; 3] {
+        ["recency", "frecency", "context"]
+    }
+}
+
+impl core::str::FromStr for RankingKind {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "recency" => Ok(Self::Recency),
+            "frecency" => Ok(Self::Frecency),
+            "context" => Ok(Self::Context),
+            _ => Err("Could not parse RankingKind"),
+        }
+    }
+}
+
+impl std::fmt::Display for RankingKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RankingKind::Recency => write!(f, "recency"),
+            RankingKind::Frecency => write!(f, "frecency"),
+            RankingKind::Context => write!(f, "context"),
+        }
+    }
+}
+
+/// Frecency is `(run_count * runs + executions * rows + recency)`, halved
+/// for every `half_life_hours` since the command last ran. `runs` is the sum
+/// of `run_count` and `rows` the number of history rows for the command.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FrecencyWeights {
+    pub run_count: f64,
+    pub executions: f64,
+    pub recency: f64,
+    pub half_life_hours: f64,
+}
+
+impl Default for FrecencyWeights {
+    fn default() -> Self {
+        Self {
+            run_count: 1.0,
+            executions: 1.0,
+            recency: 1.0,
+            half_life_hours: 24.0 * 7.0,
+        }
+    }
+}
+
+impl FrecencyWeights {
+    pub fn score(&self, runs: i64, rows: i64, age: chrono::Duration) -> f64 {
+        let hours = age.num_seconds().max(0) as f64 / 3600.0;
+        let decay = 0.5f64.powf(hours / self.half_life_hours);
+        (self.run_count * runs as f64 + self.executions * rows as f64 + self.recency) * decay
+    }
+}
+
+/// The context ranking adds, to the frecency score, `cwd` for every time the
+/// command ran in the context's cwd, `session` for every time it ran in the
+/// context's session and `follows` for every time it ran right after the
+/// context's last command in the same session.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContextWeights {
+    pub frecency: FrecencyWeights,
+    pub cwd: f64,
+    pub session: f64,
+    pub follows: f64,
+}
+
+impl Default for ContextWeights {
+    fn default() -> Self {
+        Self {
+            frecency: FrecencyWeights::default(),
+            cwd: 4.0,
+            session: 2.0,
+            follows: 8.0,
+        }
+    }
+}
+
+/// Where the search is made from
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchContext {
+    pub cwd: Option<String>,
+    pub session_id: Option<i64>,
+    /// The command line run just before this search
+    pub last_command_line: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchOptions {
+    pub limit: Option<i64>,
+    pub mode: SearchMode,
+    pub field: SearchField,
+    pub case: CaseMode,
+    pub exit_status: ExitFilter,
+    /// Only commands run in this directory or a directory under it, see
+    /// `cwd_forms` for how it's resolved
+    pub cwd_under: Option<String>,
+    /// What makes rows the same command, of which only the newest is found
+    pub unique_by: UniqueKey,
+    pub ranking: Ranking,
+    /// Used by `Ranking::Context`
+    pub context: Option<SearchContext>,
+}
+
+/// A `search`, `search_ranked` or `list_ranked` result
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub item: HistoryItem,
+    /// What the results were ordered by, higher first
+    pub score: f64,
+    /// The field the query matched, never `SearchField::Any`
+    pub field: SearchField,
+    /// Byte ranges of every match in the text of `field`
+    pub matches: Vec<Range<usize>>,
+}
+
+impl SearchHit {
+    /// The text of the field the query matched
+    pub fn text(&self) -> &str {
+        self.field.text(&self.item)
+    }
+}
+
+/// A `predict_next` result
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Prediction {
+    pub command_line: String,
+    /// How many times it followed the last command line
+    pub count: i64,
+    /// `count` out of every command line that followed the last one
+    pub probability: f64,
+}
+
+/// Selects the rows touched by `select_where`, `update_where` and
+/// `delete_where`. Every field that is set must match; an empty filter
+/// matches everything.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HistoryFilter {
+    /// Text contained in the command line or the command, case sensitive
+    pub contains: Option<String>,
+    /// Exact command
+    pub command: Option<String>,
+    /// Exact working directory
+    pub cwd: Option<String>,
+    /// Working directory is this directory or under it, taken as written
+    pub cwd_prefix: Option<String>,
+    /// Working directory is this directory or under it, see `cwd_forms`
+    pub cwd_under: Option<String>,
+    pub session_id: Option<i64>,
+    pub exit_status: ExitFilter,
+    /// Run at or after this time
+    pub from: Option<DateTime<Utc>>,
+    /// Run at or before this time
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl HistoryFilter {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Build a where clause, numbering its parameters after `offset` others
+    fn to_sql(&self, offset: usize) -> (String, Vec<Value>) {
+        let mut clauses: Vec<String> = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+
+        if let Some(contains) = &self.contains {
+            values.push(contains.clone().into());
+            clauses.push(format!(
+                "(instr(command_line, ?{n}) > 0 or instr(command, ?{n}) > 0)",
+                n = offset + values.len()
+            ));
+        }
+        if let Some(command) = &self.command {
+            values.push(command.clone().into());
+            clauses.push(format!("command = ?{}", offset + values.len()));
+        }
+        if let Some(cwd) = &self.cwd {
+            values.push(cwd.clone().into());
+            clauses.push(format!("cwd = ?{}", offset + values.len()));
+        }
+        if let Some(prefix) = &self.cwd_prefix {
+            let dir = trim_separators(prefix).to_string();
+            clauses.push(cwd_under_sql("cwd", &[dir], offset, &mut values));
+        }
+        if let Some(dir) = &self.cwd_under {
+            clauses.push(cwd_under_sql("cwd", &cwd_forms(dir), offset, &mut values));
+        }
+        if let Some(session_id) = self.session_id {
+            values.push(session_id.into());
+            clauses.push(format!("session_id = ?{}", offset + values.len()));
+        }
+        if let Some(clause) = self.exit_status.to_sql("exit_status") {
+            clauses.push(clause);
+        }
+        if let Some(from) = self.from {
+            values.push(from.timestamp_nanos().into());
+            clauses.push(format!("timestamp >= ?{}", offset + values.len()));
+        }
+        if let Some(to) = self.to {
+            values.push(to.timestamp_nanos().into());
+            clauses.push(format!("timestamp <= ?{}", offset + values.len()));
+        }
+
+        if clauses.is_empty() {
+            ("1 = 1".to_string(), values)
+        } else {
+            (clauses.join(" and "), values)
+        }
+    }
+}
+
+/// The columns `update_where` changes on every matching row
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HistoryUpdate {
+    pub command_line: Option<String>,
+    pub command: Option<String>,
+    pub command_params: Option<String>,
+    pub cwd: Option<String>,
+    /// Move working directories from `from` or under it to the same place
+    /// under `to`, e.g. to move everything under an old checkout path.
+    /// Others are left alone, so `/old-2` doesn't move along with `/old`.
+    pub move_cwd: Option<(String, String)>,
+}
+
+/// Columns with the SQL of their new values
+type Assignments = Vec<(&'static str, String)>;
+
+impl HistoryUpdate {
+    /// The new value of every column that changes, numbering parameters
+    /// from 1
+    fn to_sql(&self) -> Result<(Assignments, Vec<Value>)> {
+        let mut sets: Assignments = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+
+        if self.cwd.is_some() && self.move_cwd.is_some() {
+            bail!("a working directory can't be both set and moved");
+        }
+
+        // a new command line alone brings its command and parameters along,
+        // so that they keep matching it
+        let (command, command_params) = match (&self.command_line, &self.command) {
+            (Some(command_line), None) if self.command_params.is_none() => {
+                let (command, params) = split_command_line(command_line);
+                (Some(Value::from(command)), Some(Value::from(params)))
+            }
+            _ => (
+                self.command.clone().map(Value::from),
+                self.command_params.clone().map(Value::from),
+            ),
+        };
+        for (column, value) in [
+            ("command_line", self.command_line.clone().map(Value::from)),
+            ("command", command),
+            ("command_params", command_params),
+            ("cwd", self.cwd.clone().map(Value::from)),
+        ] {
+            if let Some(value) = value {
+                values.push(value);
+                sets.push((column, format!("?{}", values.len())));
+            }
+        }
+        if let Some((from, to)) = &self.move_cwd {
+            let (from, to) = (trim_separators(from), trim_separators(to));
+            // only rows equal to `from` or starting with `from/` move, and
+            // keep the separator in front of what's under it
+            let root = |dir: &str| dir.trim_end_matches(std::path::is_separator).to_string();
+            values.push(from.to_string().into());
+            values.push(format!("{}{}", root(from), std::path::MAIN_SEPARATOR).into());
+            values.push(to.to_string().into());
+            values.push(root(to).into());
+            let n = values.len() - 3;
+            sets.push((
+                "cwd",
+                format!(
+                    "case when history_items.cwd = ?{f} then ?{t}
+                    when substr(history_items.cwd, 1, length(?{b})) = ?{b}
+                    then ?{tb} || substr(history_items.cwd, length(?{b}))
+                    else history_items.cwd end",
+                    f = n,
+                    b = n + 1,
+                    t = n + 2,
+                    tb = n + 3
+                ),
+            ));
+        }
+
+        Ok((sets, values))
+    }
+}
+
+/// The set and where clauses of `update_where`. Rows that `changes` leaves
+/// as they are, or that would end up with the `timestamp`, `cwd` and
+/// `command` of another row, aren't selected.
+fn update_sql(
+    filter: &HistoryFilter,
+    changes: &HistoryUpdate,
+) -> Result<(String, String, Vec<Value>)> {
+    if filter.is_empty() {
+        bail!("refusing to update every history item, the filter is empty");
+    }
+    let (sets, mut values) = changes.to_sql()?;
+    if sets.is_empty() {
+        bail!("nothing to update");
+    }
+    let (clause, filter_values) = filter.to_sql(values.len());
+    values.extend(filter_values);
+
+    let new = |column: &str| {
+        sets.iter()
+            .find(|(c, _)| *c == column)
+            .map_or(format!("history_items.{}", column), |(_, v)| v.clone())
+    };
+    let set = sets
+        .iter()
+        .map(|(c, v)| format!("{} = {}", c, v))
+        .join(", ");
+    let changed = sets
+        .iter()
+        .map(|(c, v)| format!("{} is not {}", c, v))
+        .join(" or ");
+    let clause = format!(
+        "{} and ({}) and not exists (select 1 from history_items other
+            where other.timestamp = history_items.timestamp and other.cwd = {}
+            and other.command = {} and other.history_id != history_items.history_id)",
+        clause,
+        changed,
+        new("cwd"),
+        new("command")
+    );
+
+    Ok((set, clause, values))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_history_item(db: &mut impl Database, cmd: &str) -> Result<()> {
+        let history = HistoryItem::new(
+            None,
+            cmd.to_string(),
+            cmd.to_string(),
+            None,
+            "/home/ellie".to_string(),
+            0,
+            0,
+            Some(1),
+            chrono::Utc::now(),
+            1,
+        );
+        db.save(&history)
+    }
+
+    #[test]
+    fn test_search_prefix() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        new_history_item(&mut db, "ls /home/ellie").unwrap();
+
+        let mut results = db.search(None, SearchMode::Prefix, "ls").unwrap();
+        assert_eq!(results.len(), 1);
+
+        results = db.search(None, SearchMode::Prefix, "/home").unwrap();
+        assert_eq!(results.len(), 0);
+
+        results = db.search(None, SearchMode::Prefix, "ls  ").unwrap();
+        assert_eq!(results.len(), 0);
+    }
+
+    #[test]
+    fn test_search_fulltext() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        new_history_item(&mut db, "ls /home/ellie").unwrap();
+
+        let mut results = db.search(None, SearchMode::FullText, "ls").unwrap();
+        assert_eq!(results.len(), 1);
+
+        results = db.search(None, SearchMode::FullText, "/home").unwrap();
+        assert_eq!(results.len(), 1);
+
+        results = db.search(None, SearchMode::FullText, "ls  ").unwrap();
+        assert_eq!(results.len(), 0);
+    }
+
+    #[test]
+    fn test_search_fuzzy() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        new_history_item(&mut db, "ls /home/ellie").unwrap();
+        new_history_item(&mut db, "ls /home/frank").unwrap();
+        new_history_item(&mut db, "cd /home/ellie").unwrap();
+        new_history_item(&mut db, "/home/ellie/.bin/rustup").unwrap();
+
+        let mut results = db.search(None, SearchMode::Fuzzy, "ls /").unwrap();
+        assert_eq!(results.len(), 2);
+
+        results = db.search(None, SearchMode::Fuzzy, "l/h/").unwrap();
+        assert_eq!(results.len(), 2);
+
+        results = db.search(None, SearchMode::Fuzzy, "/h/e").unwrap();
+        assert_eq!(results.len(), 3);
+
+        results = db.search(None, SearchMode::Fuzzy, "/hmoe/").unwrap();
+        assert_eq!(results.len(), 0);
+
+        results = db.search(None, SearchMode::Fuzzy, "ellie/home").unwrap();
+        assert_eq!(results.len(), 0);
+
+        results = db.search(None, SearchMode::Fuzzy, "lsellie").unwrap();
+        assert_eq!(results.len(), 1);
+
+        results = db.search(None, SearchMode::Fuzzy, " ").unwrap();
+        assert_eq!(results.len(), 3);
+    }
+
+    fn temp_db_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("rsq-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_subscribe_in_process() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        let changes = db.subscribe().unwrap();
+
+        new_history_item(&mut db, "ls /home/ellie").unwrap();
+        let mut item = db.last().unwrap();
+        let id = item.history_id.unwrap();
+        item.cwd = "/tmp".to_string();
+        db.update(&item).unwrap();
+        db.delete_history_item(id).unwrap();
+
+        let events: Vec<_> = changes.try_iter().collect();
+        assert_eq!(
+            events,
+            vec![
+                HistoryEvent {
+                    action: HistoryAction::Inserted,
+                    history_id: Some(id)
+                },
+                HistoryEvent {
+                    action: HistoryAction::Updated,
+                    history_id: Some(id)
+                },
+                HistoryEvent {
+                    action: HistoryAction::Deleted,
+                    history_id: Some(id)
+                },
+            ]
+        );
+
+        // our own changes must not be delivered twice
+        assert_eq!(db.poll_changes().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_subscribe_rollback() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        let changes = db.subscribe().unwrap();
+
+        let tx = db.conn.transaction().unwrap();
+        tx.execute("delete from history_items", []).unwrap();
+        tx.execute(
+            "insert into history_items (command_line, command, command_params, cwd, duration, exit_status, session_id, timestamp, run_count) values ('ls', 'ls', '', '/', 0, 0, 1, 1, 1)",
+            [],
+        )
+        .unwrap();
+        tx.rollback().unwrap();
+
+        assert_eq!(changes.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn test_subscribe_across_connections() {
+        let path = temp_db_path("subscribe");
+        let mut writer = Sqlite::new(&path, SqlLogMode::Disabled).unwrap();
+        let reader = Sqlite::new(&path, SqlLogMode::Disabled).unwrap();
+        let changes = reader.subscribe().unwrap();
+
+        assert_eq!(reader.poll_changes().unwrap(), 0);
+
+        new_history_item(&mut writer, "cargo fmt").unwrap();
+        new_history_item(&mut writer, "cargo clippy").unwrap();
+        let id = writer.last().unwrap().history_id.unwrap();
+        writer.delete_history_item(id).unwrap();
+
+        assert_eq!(reader.poll_changes().unwrap(), 3);
+        let events: Vec<_> = changes.try_iter().map(|e| e.action).collect();
+        assert_eq!(
+            events,
+            vec![
+                HistoryAction::Inserted,
+                HistoryAction::Inserted,
+                HistoryAction::Deleted
+            ]
+        );
+
+        // nothing new since the last poll
+        assert_eq!(reader.poll_changes().unwrap(), 0);
+
+        drop(writer);
+        drop(reader);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_subscribe_reports_pruned_changes() {
+        let path = temp_db_path("subscribe-gap");
+        let mut writer = Sqlite::new(&path, SqlLogMode::Disabled).unwrap();
+        let reader = Sqlite::new(&path, SqlLogMode::Disabled).unwrap();
+        let changes = reader.subscribe().unwrap();
+
+        new_history_item(&mut writer, "cargo fmt").unwrap();
+        // as if the reader had been away for more than 10000 changes
+        writer.execute("delete from history_changes", []).unwrap();
+        new_history_item(&mut writer, "cargo clippy").unwrap();
+
+        assert_eq!(reader.poll_changes().unwrap(), 2);
+        let events: Vec<_> = changes
+            .try_iter()
+            .map(|e| (e.action, e.history_id.is_some()))
+            .collect();
+        assert_eq!(
+            events,
+            vec![(HistoryAction::Gap, false), (HistoryAction::Inserted, true)]
+        );
+
+        drop(writer);
+        drop(reader);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_unsubscribe_removes_hooks() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        drop(db.subscribe().unwrap());
+
+        // the first write finds the receiver gone, the next one cleans up
+        new_history_item(&mut db, "ls").unwrap();
+        assert_eq!(db.poll_changes().unwrap(), 0);
+        new_history_item(&mut db, "ls -l").unwrap();
+        assert!(!db.notifier.lock().unwrap().hooks_installed);
+
+        let changes = db.subscribe().unwrap();
+        new_history_item(&mut db, "ls -a").unwrap();
+        assert_eq!(changes.try_iter().count(), 1);
+    }
+
+    fn new_history_item_in(db: &mut impl Database, cmd: &str, cwd: &str) -> Result<()> {
+        let history = HistoryItem::new(
+            None,
+            cmd.to_string(),
+            cmd.to_string(),
+            None,
+            cwd.to_string(),
+            0,
+            0,
+            Some(1),
+            chrono::Utc::now(),
+            1,
+        );
+        db.save(&history)
+    }
+
+    #[test]
+    fn test_delete_where() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        new_history_item(&mut db, "export TOKEN=hunter2").unwrap();
+        new_history_item(&mut db, "curl -H 'auth: hunter2' example.com").unwrap();
+        new_history_item(&mut db, "ls").unwrap();
+
+        let filter = HistoryFilter {
+            contains: Some("hunter2".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(db.select_where(&filter).unwrap().len(), 2);
+        assert_eq!(db.delete_where(&filter, true).unwrap(), 2);
+        assert_eq!(db.history_count().unwrap(), 3);
+
+        assert_eq!(db.delete_where(&filter, false).unwrap(), 2);
+        assert_eq!(db.history_count().unwrap(), 1);
+
+        assert!(db.delete_where(&HistoryFilter::default(), false).is_err());
+        assert_eq!(db.history_count().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_update_where_move_cwd() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        new_history_item_in(&mut db, "make", "/old/checkout").unwrap();
+        new_history_item_in(&mut db, "make test", "/old/checkout/src").unwrap();
+        new_history_item_in(&mut db, "make", "/old/checkout-2").unwrap();
+
+        let filter = HistoryFilter {
+            cwd_prefix: Some("/old/checkout/".to_string()),
+            ..Default::default()
+        };
+        let changes = HistoryUpdate {
+            move_cwd: Some(("/old/checkout".to_string(), "/new/".to_string())),
+            ..Default::default()
+        };
+        assert_eq!(db.update_where(&filter, &changes, true).unwrap(), 2);
+        assert_eq!(db.list(None, false).unwrap()[1].cwd, "/old/checkout/src");
+
+        assert_eq!(db.update_where(&filter, &changes, false).unwrap(), 2);
+        let cwds: Vec<_> = db
+            .list(None, false)
+            .unwrap()
+            .into_iter()
+            .map(|h| h.cwd)
+            .collect();
+        assert_eq!(cwds, vec!["/old/checkout-2", "/new/src", "/new"]);
+
+        // a moved directory that isn't under the filtered one stays put, and
+        // isn't counted as changed
+        let filter = HistoryFilter {
+            cwd: Some("/old/checkout-2".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(db.update_where(&filter, &changes, false).unwrap(), 0);
+        assert_eq!(db.list(None, false).unwrap()[0].cwd, "/old/checkout-2");
+
+        assert!(db
+            .update_where(&filter, &HistoryUpdate::default(), false)
+            .is_err());
+        let both = HistoryUpdate {
+            cwd: Some("/new".to_string()),
+            ..changes
+        };
+        assert!(db.update_where(&filter, &both, false).is_err());
+    }
+
+    #[test]
+    fn test_update_where_changed_rows() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        let now = chrono::Utc::now();
+        for cwd in ["/home/ellie", "/tmp"] {
+            let history = HistoryItem::new(
+                None,
+                "make".to_string(),
+                "make".to_string(),
+                None,
+                cwd.to_string(),
+                0,
+                0,
+                Some(1),
+                now,
+                1,
+            );
+            db.save(&history).unwrap();
+        }
+        let changes_before: i64 = db
+            .conn
+            .query_row("select count(*) from history_changes", [], |r| r.get(0))
+            .unwrap();
+
+        // setting what is already there changes nothing
+        let filter = HistoryFilter {
+            command: Some("make".to_string()),
+            ..Default::default()
+        };
+        let same = HistoryUpdate {
+            command: Some("make".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(db.update_where(&filter, &same, true).unwrap(), 0);
+        assert_eq!(db.update_where(&filter, &same, false).unwrap(), 0);
+        let changes_after: i64 = db
+            .conn
+            .query_row("select count(*) from history_changes", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(changes_after, changes_before);
+
+        // /tmp would become a second make in /home/ellie at the same time
+        let filter = HistoryFilter {
+            cwd: Some("/tmp".to_string()),
+            ..Default::default()
+        };
+        let home = HistoryUpdate {
+            cwd: Some("/home/ellie".to_string()),
+            ..Default::default()
+        };
+        assert!(db.select_update(&filter, &home).unwrap().is_empty());
+        assert_eq!(db.update_where(&filter, &home, true).unwrap(), 0);
+
+        // a new command line brings its command and parameters along
+        let rebuild = HistoryUpdate {
+            command_line: Some("make -j8 all".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(db.select_update(&filter, &rebuild).unwrap().len(), 1);
+        assert_eq!(db.update_where(&filter, &rebuild, false).unwrap(), 1);
+        let rebuilt = db.select_where(&filter).unwrap().remove(0);
+        assert_eq!(rebuilt.command_line, "make -j8 all");
+        assert_eq!(rebuilt.command, "make");
+        assert_eq!(rebuilt.command_params.as_deref(), Some("-j8 all"));
+    }
+
+    #[test]
+    fn test_transaction_commit_and_rollback() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        new_history_item(&mut db, "ls").unwrap();
+
+        let count = db
+            .transaction(|tx| {
+                new_history_item(tx, "cargo fmt")?;
+                new_history_item(tx, "cargo clippy")?;
+                tx.history_count()
+            })
+            .unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(db.history_count().unwrap(), 3);
+
+        let result: Result<()> = db.transaction(|tx| {
+            new_history_item(tx, "git commit")?;
+            let filter = HistoryFilter {
+                command: Some("ls".to_string()),
+                ..Default::default()
+            };
+            tx.delete_where(&filter, false)?;
+            bail!("merge failed")
+        });
+        assert!(result.is_err());
+        assert_eq!(db.history_count().unwrap(), 3);
+        assert!(db.conn.is_autocommit());
+    }
+
+    #[test]
+    fn test_transaction_handle() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        let changes = db.subscribe().unwrap();
+        let filter = HistoryFilter {
+            command: Some("ls".to_string()),
+            ..Default::default()
+        };
+
+        {
+            let mut tx = db.begin().unwrap();
+            new_history_item(&mut *tx, "ls").unwrap();
+            // a dry run inside the transaction only undoes itself
+            assert_eq!(tx.delete_where(&filter, true).unwrap(), 1);
+            assert_eq!(tx.history_count().unwrap(), 1);
+            assert_eq!(changes.try_iter().count(), 0);
+            tx.commit().unwrap();
+        }
+        assert_eq!(db.history_count().unwrap(), 1);
+        let events: Vec<_> = changes.try_iter().map(|e| e.action).collect();
+        assert_eq!(events, vec![HistoryAction::Inserted]);
+
+        {
+            let tx = db.begin().unwrap();
+            tx.delete_where(&filter, false).unwrap();
+            // dropped without commit
+        }
+        assert_eq!(db.history_count().unwrap(), 1);
+        assert_eq!(changes.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn test_open_read_only_and_snapshot() {
+        let path = temp_db_path("snapshot");
+        assert!(Sqlite::open(&path, SqlLogMode::Disabled, OpenMode::ReadOnly).is_err());
+
+        let mut writer = Sqlite::new(&path, SqlLogMode::Disabled).unwrap();
+        new_history_item(&mut writer, "cargo fmt").unwrap();
+
+        let mut reader = Sqlite::open(&path, SqlLogMode::Disabled, OpenMode::ReadOnly).unwrap();
+        let snapshot = Sqlite::open(&path, SqlLogMode::Disabled, OpenMode::Snapshot).unwrap();
+        assert!(new_history_item(&mut reader, "rm -rf /").is_err());
+
+        new_history_item(&mut writer, "cargo clippy").unwrap();
+        assert_eq!(reader.history_count().unwrap(), 2);
+        assert_eq!(snapshot.history_count().unwrap(), 1);
+        assert_eq!(snapshot.last().unwrap().command, "cargo fmt");
+        // the newest rows are only in the -wal, which immutable would skip
+        assert!(Sqlite::open(&path, SqlLogMode::Disabled, OpenMode::Immutable).is_err());
+
+        // the last connection to close checkpoints the -wal, if it can write
+        drop(reader);
+        drop(snapshot);
+        drop(writer);
+        let immutable = Sqlite::open(&path, SqlLogMode::Disabled, OpenMode::Immutable).unwrap();
+        assert_eq!(immutable.history_count().unwrap(), 2);
+        drop(immutable);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_attach() {
+        let archive_path = temp_db_path("attach-2020");
+        let mut archive = Sqlite::new(&archive_path, SqlLogMode::Disabled).unwrap();
+        new_history_item(&mut archive, "cargo build").unwrap();
+        new_history_item(&mut archive, "ls /home/ellie").unwrap();
+        drop(archive);
+
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        new_history_item(&mut db, "cargo test").unwrap();
+        assert!(db.list(None, false).unwrap()[0].source.is_none());
+
+        assert!(db.attach(&archive_path, "main").is_err());
+        assert!(db.attach(&archive_path, "y2020; drop").is_err());
+        db.attach(&archive_path, "y2020").unwrap();
+
+        assert_eq!(db.history_count().unwrap(), 3);
+        let sources: Vec<_> = db
+            .search(None, SearchMode::Prefix, "cargo")
+            .unwrap()
+            .into_iter()
+            .map(|h| (h.item.command, h.item.source.unwrap()))
+            .collect();
+        assert_eq!(
+            sources,
+            vec![
+                ("cargo test".to_string(), "main".to_string()),
+                ("cargo build".to_string(), "y2020".to_string()),
+            ]
+        );
+        assert_eq!(db.list(Some(2), true).unwrap().len(), 2);
+        let from = chrono::Utc::now() - chrono::Duration::days(1);
+        assert_eq!(db.range(from, chrono::Utc::now()).unwrap().len(), 3);
+        assert_eq!(db.first().unwrap().source.as_deref(), Some("y2020"));
+        assert_eq!(db.last().unwrap().command, "cargo test");
+        assert_eq!(db.before(chrono::Utc::now(), 10).unwrap().len(), 3);
+        let suggested = db
+            .suggest("ls", &SearchContext::default(), &ContextWeights::default())
+            .unwrap();
+        assert_eq!(suggested.as_deref(), Some("ls /home/ellie"));
+
+        db.detach("y2020").unwrap();
+        assert_eq!(db.history_count().unwrap(), 1);
+
+        // names that aren't identifiers, like the stem of `2020.db`, work too
+        db.attach(&archive_path, "2020").unwrap();
+        assert_eq!(db.history_count().unwrap(), 3);
+        assert_eq!(db.list(Some(1), false).unwrap().len(), 1);
+        db.detach("2020").unwrap();
+        assert_eq!(db.history_count().unwrap(), 1);
+
+        drop(db);
+        let _ = std::fs::remove_file(&archive_path);
+    }
+
+    #[test]
+    fn test_fuzzy_search_ranking() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        new_history_item(&mut db, "cargo build").unwrap();
+        new_history_item(&mut db, "cat ./args/go.txt").unwrap();
+        new_history_item(&mut db, "echo 100%_done").unwrap();
+
+        let search = |limit: Option<i64>, query: &str| {
+            let options = SearchOptions {
+                limit,
+                mode: SearchMode::Fuzzy,
+                field: SearchField::Command,
+                ..Default::default()
+            };
+            db.search_ranked(query, &options).unwrap()
+        };
+
+        // the tight match wins even though the scattered one is newer
+        let hits = search(None, "cargo");
+        let commands: Vec<_> = hits.iter().map(|h| h.item.command.as_str()).collect();
+        assert_eq!(commands, vec!["cargo build", "cat ./args/go.txt"]);
+        assert!(hits[0].score > hits[1].score);
+        assert_eq!(hits[0].matches, vec![0..5]);
+
+        assert_eq!(search(Some(1), "cargo").len(), 1);
+        assert_eq!(search(None, "%_").len(), 1);
+        assert_eq!(search(None, "_d").len(), 1);
+    }
+
+    #[test]
+    fn test_search_regex() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        new_history_item(&mut db, "git rebase -i HEAD~3").unwrap();
+        new_history_item(&mut db, "git cherry-pick abc123").unwrap();
+        new_history_item(&mut db, "git commit -m 'rebase'").unwrap();
+        new_history_item(&mut db, "tig rebase").unwrap();
+
+        let results = db
+            .search(None, SearchMode::Regex, "^git (rebase|cherry-pick)")
+            .unwrap();
+        assert_eq!(results.len(), 2);
+
+        // `*` is a regex operator here, not a wildcard
+        let results = db.search(None, SearchMode::Regex, "^t.*e$").unwrap();
+        assert_eq!(results[0].item.command, "tig rebase");
+
+        let err = db
+            .search(None, SearchMode::Regex, "git (rebase")
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid regular expression"));
+    }
+
+    fn new_history_item_at(
+        db: &mut impl Database,
+        cmd: &str,
+        ago: chrono::Duration,
+        run_count: i64,
+    ) -> Result<()> {
+        let history = HistoryItem::new(
+            None,
+            cmd.to_string(),
+            cmd.to_string(),
+            None,
+            "/home/ellie".to_string(),
+            0,
+            0,
+            Some(1),
+            chrono::Utc::now() - ago,
+            run_count,
+        );
+        db.save(&history)
+    }
+
+    #[test]
+    fn test_frecency() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        for day in 25..30 {
+            new_history_item_at(&mut db, "cargo test", chrono::Duration::days(day), 40).unwrap();
+        }
+        new_history_item_at(&mut db, "cat notes.txt", chrono::Duration::minutes(5), 1).unwrap();
+
+        let recency = db.list_ranked(None, &Ranking::Recency).unwrap();
+        assert_eq!(recency[0].item.command, "cat notes.txt");
+        assert_eq!(recency.len(), 2);
+
+        let frecency = Ranking::Frecency(FrecencyWeights::default());
+        let hits = db.list_ranked(None, &frecency).unwrap();
+        assert_eq!(hits[0].item.command, "cargo test");
+        assert!(hits[0].score > hits[1].score);
+
+        // with a short half life recency wins again
+        let options = SearchOptions {
+            mode: SearchMode::Prefix,
+            ranking: Ranking::Frecency(FrecencyWeights {
+                half_life_hours: 24.0,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let hits = db.search_ranked("ca", &options).unwrap();
+        assert_eq!(hits[0].item.command, "cat notes.txt");
+
+        let options = SearchOptions {
+            limit: Some(1),
+            mode: SearchMode::FullText,
+            ranking: frecency,
+            ..Default::default()
+        };
+        let hits = db.search_ranked("test", &options).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].item.command, "cargo test");
+    }
+
+    fn new_history_item_ctx(
+        db: &mut impl Database,
+        cmd: &str,
+        cwd: &str,
+        session_id: i64,
+        ago: chrono::Duration,
+    ) -> Result<()> {
+        let history = HistoryItem::new(
+            None,
+            cmd.to_string(),
+            cmd.to_string(),
+            None,
+            cwd.to_string(),
+            0,
+            0,
+            Some(session_id),
+            chrono::Utc::now() - ago,
+            1,
+        );
+        db.save(&history)
+    }
+
+    #[test]
+    fn test_context_ranking() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        let minutes = chrono::Duration::minutes;
+        new_history_item_ctx(&mut db, "make deploy", "/src/infra", 1, minutes(50)).unwrap();
+        new_history_item_ctx(&mut db, "cargo fmt", "/src/rsq", 2, minutes(40)).unwrap();
+        new_history_item_ctx(&mut db, "cargo clippy", "/src/rsq", 2, minutes(39)).unwrap();
+        new_history_item_ctx(&mut db, "make test", "/src/rsq", 3, minutes(30)).unwrap();
+        new_history_item_ctx(&mut db, "cargo fmt", "/src/rsq", 3, minutes(20)).unwrap();
+        new_history_item_ctx(&mut db, "cargo clippy", "/src/rsq", 3, minutes(19)).unwrap();
+        new_history_item_ctx(&mut db, "make all", "/tmp", 4, minutes(1)).unwrap();
+
+        let commands = |context: Option<SearchContext>, query: &str| -> Vec<String> {
+            let options = SearchOptions {
+                ranking: Ranking::Context(ContextWeights::default()),
+                context,
+                ..Default::default()
+            };
+            db.search_ranked(query, &options)
+                .unwrap()
+                .into_iter()
+                .map(|h| h.item.command)
+                .collect()
+        };
+
+        let in_infra = SearchContext {
+            cwd: Some("/src/infra".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(commands(Some(in_infra), "make")[0], "make deploy");
+
+        let in_session = SearchContext {
+            session_id: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(commands(Some(in_session), "make")[0], "make test");
+
+        let after_fmt = SearchContext {
+            last_command_line: Some("cargo fmt".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(commands(Some(after_fmt), "cargo")[0], "cargo clippy");
+        let after = |line: &str| SearchContext {
+            last_command_line: Some(line.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(commands(Some(after("make test")), "cargo")[0], "cargo fmt");
+        // only the whole command line counts
+        assert_eq!(commands(Some(after("make")), "cargo")[0], "cargo clippy");
+
+        // without a context it's plain frecency, which favours the newest
+        assert_eq!(commands(None, "make")[0], "make all");
+    }
+
+    #[test]
+    fn test_boolean_search() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        new_history_item(&mut db, "docker build --no-cache .").unwrap();
+        new_history_item(&mut db, "docker compose build --no-cache").unwrap();
+        new_history_item(&mut db, "docker build .").unwrap();
+        new_history_item_in(&mut db, "podman build --no-cache .", "/home/ellie/docker").unwrap();
+
+        let search = |field: SearchField, query: &str| {
+            let options = SearchOptions {
+                mode: SearchMode::Boolean,
+                field,
+                ..Default::default()
+            };
+            db.search_ranked(query, &options)
+                .unwrap()
+                .into_iter()
+                .map(|hit| hit.item.command)
+                .sorted()
+                .collect_vec()
+        };
+
+        assert_eq!(
+            search(
+                SearchField::Command,
+                r#"docker -compose "build --no-cache""#
+            ),
+            vec!["docker build --no-cache ."]
+        );
+        assert_eq!(
+            search(
+                SearchField::Command,
+                "(docker OR podman) NOT compose --no-cache"
+            ),
+            vec!["docker build --no-cache .", "podman build --no-cache ."]
+        );
+        assert_eq!(
+            search(SearchField::Command, r#""build .""#),
+            vec!["docker build ."]
+        );
+        // a word matches when it's in any of the fields
+        assert_eq!(search(SearchField::Any, "DOCKER podman").len(), 0);
+        assert_eq!(
+            search(SearchField::Any, "docker podman"),
+            vec!["podman build --no-cache ."]
+        );
+        assert_eq!(search(SearchField::Command, "").len(), 4);
+
+        let options = SearchOptions {
+            mode: SearchMode::Boolean,
+            ..Default::default()
+        };
+        let hits = db.search_ranked("build -compose cache", &options).unwrap();
+        let hit = hits
+            .iter()
+            .find(|h| h.item.command.starts_with("docker build --"))
+            .unwrap();
+        let matched = hit
+            .matches
+            .iter()
+            .map(|r| &hit.text()[r.clone()])
+            .collect_vec();
+        assert_eq!(matched, vec!["build", "cache"]);
+
+        assert!(db.search_ranked("(docker", &options).is_err());
+    }
+
+    #[test]
+    fn test_match_ranges() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        new_history_item(&mut db, "git log --grep=STRASSE").unwrap();
+
+        let find = |mode: SearchMode, field: SearchField, query: &str| {
+            let options = SearchOptions {
+                mode,
+                field,
+                ..Default::default()
+            };
+            let hit = db.search_ranked(query, &options).unwrap().remove(0);
+            let text = hit.text().to_string();
+            let matched = hit.matches.iter().map(|r| &text[r.clone()]).join("|");
+            (hit.field, matched)
+        };
+
+        assert_eq!(
+            find(SearchMode::Prefix, SearchField::Command, "gi"),
+            (SearchField::Command, "gi".to_string())
+        );
+        assert_eq!(
+            find(SearchMode::FullText, SearchField::CommandLine, "g*t"),
+            (SearchField::CommandLine, "git|g --grep=ST".to_string())
+        );
+        assert_eq!(
+            find(SearchMode::FullText, SearchField::Any, "straße"),
+            (SearchField::Command, "STRASSE".to_string())
+        );
+        assert_eq!(
+            find(SearchMode::Regex, SearchField::Any, "ellie$"),
+            (SearchField::Cwd, "ellie".to_string())
+        );
+        assert_eq!(
+            find(SearchMode::Fuzzy, SearchField::CommandLine, "loggrep"),
+            (SearchField::CommandLine, "log|grep".to_string())
+        );
+    }
+
+    #[test]
+    fn test_case_modes() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        new_history_item(&mut db, "ls Makefile").unwrap();
+        new_history_item(&mut db, "ls makefile.old").unwrap();
+        new_history_item(&mut db, "grep STRASSE Ärzte.txt").unwrap();
+
+        let count = |mode: SearchMode, case: CaseMode, query: &str| {
+            let options = SearchOptions {
+                mode,
+                field: SearchField::CommandLine,
+                case,
+                ..Default::default()
+            };
+            db.search_ranked(query, &options).unwrap().len()
+        };
+
+        for mode in [SearchMode::FullText, SearchMode::Fuzzy, SearchMode::Regex] {
+            assert_eq!(count(mode, CaseMode::Smart, "makefile"), 2);
+            assert_eq!(count(mode, CaseMode::Smart, "Makefile"), 1);
+            assert_eq!(count(mode, CaseMode::Sensitive, "makefile"), 1);
+            assert_eq!(count(mode, CaseMode::Insensitive, "Makefile"), 2);
+            assert_eq!(count(mode, CaseMode::Smart, "ärzte"), 1);
+            assert_eq!(count(mode, CaseMode::Sensitive, "ärzte"), 0);
+        }
+        assert_eq!(count(SearchMode::Prefix, CaseMode::Smart, "LS"), 0);
+        assert_eq!(count(SearchMode::Prefix, CaseMode::Insensitive, "LS"), 2);
+        // full case folding
+        assert_eq!(count(SearchMode::FullText, CaseMode::Smart, "straße"), 1);
+        assert_eq!(count(SearchMode::Fuzzy, CaseMode::Smart, "straße"), 1);
+        // escapes don't make a regex case sensitive
+        assert_eq!(count(SearchMode::Regex, CaseMode::Smart, r"ls\s+m"), 2);
+        // a regex is only folded character by character, see `CaseMode`
+        assert_eq!(count(SearchMode::Regex, CaseMode::Smart, "straße"), 0);
+        // glob characters in a query are taken literally
+        assert_eq!(count(SearchMode::FullText, CaseMode::Smart, "make?ile"), 0);
+    }
+
+    #[test]
+    fn test_search_fields() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        let history = HistoryItem::new(
+            None,
+            "terraform apply -var env=prod".to_string(),
+            "terraform".to_string(),
+            Some("apply -var env=prod".to_string()),
+            "/home/ellie/src/infra".to_string(),
+            0,
+            0,
+            Some(1),
+            chrono::Utc::now(),
+            1,
+        );
+        db.save(&history).unwrap();
+        new_history_item(&mut db, "ls env=prod").unwrap();
+
+        let count = |mode: SearchMode, field: SearchField, query: &str| {
+            let options = SearchOptions {
+                mode,
+                field,
+                ..Default::default()
+            };
+            db.search_ranked(query, &options).unwrap().len()
+        };
+
+        assert_eq!(
+            count(SearchMode::FullText, SearchField::Command, "env=prod"),
+            1
+        );
+        assert_eq!(
+            count(SearchMode::FullText, SearchField::CommandLine, "env=prod"),
+            2
+        );
+        assert_eq!(count(SearchMode::Prefix, SearchField::Params, "apply"), 1);
+        assert_eq!(
+            count(
+                SearchMode::Prefix,
+                SearchField::Cwd,
+                "/home/ellie/src/infra"
+            ),
+            1
+        );
+        assert_eq!(
+            count(SearchMode::Prefix, SearchField::Cwd, "/home/ellie"),
+            2
+        );
+        assert_eq!(count(SearchMode::Regex, SearchField::Any, "src/infra$"), 1);
+        assert_eq!(count(SearchMode::Fuzzy, SearchField::Params, "avp"), 1);
+        assert_eq!(count(SearchMode::Fuzzy, SearchField::Any, "sinfra"), 1);
+    }
+
+    #[test]
+    fn test_mode_serde_names() {
+        assert_eq!(
+            serde_json::to_string(&SearchMode::FullText).unwrap(),
+            r#""fulltext""#
+        );
+        assert_eq!(
+            serde_json::from_str::<SearchMode>(r#""fuzzy""#).unwrap(),
+            SearchMode::Fuzzy
+        );
+        assert_eq!(
+            serde_json::to_string(&SqlLogMode::Profile).unwrap(),
+            r#""profile""#
+        );
+        assert_eq!(
+            serde_json::from_str::<SqlLogMode>(r#""trace""#).unwrap(),
+            SqlLogMode::Trace
+        );
+    }
+
+    fn new_history_item_exit(
+        db: &mut impl Database,
+        cmd: &str,
+        exit_status: i64,
+        ago: chrono::Duration,
+    ) -> Result<()> {
+        let history = HistoryItem::new(
+            None,
+            cmd.to_string(),
+            cmd.to_string(),
+            None,
+            "/home/ellie".to_string(),
+            1500,
+            exit_status,
+            Some(1),
+            chrono::Utc::now() - ago,
+            1,
+        );
+        db.save(&history)
+    }
+
+    #[test]
+    fn test_exit_status_filters() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        let hours = chrono::Duration::hours;
+        new_history_item_exit(&mut db, "cargo test", 101, hours(3)).unwrap();
+        new_history_item_exit(&mut db, "cargo test", 0, hours(2)).unwrap();
+        new_history_item_exit(&mut db, "make", 2, hours(1)).unwrap();
+        new_history_item_exit(&mut db, "nope", 127, hours(0)).unwrap();
+
+        let search = |exit_status: ExitFilter| {
+            let options = SearchOptions {
+                exit_status,
+                ..Default::default()
+            };
+            db.search_ranked("", &options)
+                .unwrap()
+                .into_iter()
+                .map(|hit| (hit.item.command, hit.item.exit_status))
+                .collect_vec()
+        };
+        let hit = |cmd: &str, exit_status: i64| (cmd.to_string(), exit_status);
+
+        assert_eq!(search(ExitFilter::Any).len(), 3);
+        assert_eq!(search(ExitFilter::Success), vec![hit("cargo test", 0)]);
+        // the newest failure of a command is found even after it succeeded
+        assert_eq!(
+            search(ExitFilter::Failure),
+            vec![hit("nope", 127), hit("make", 2), hit("cargo test", 101)]
+        );
+        assert_eq!(
+            search("2,101".parse().unwrap()),
+            vec![hit("make", 2), hit("cargo test", 101)]
+        );
+
+        let filter = HistoryFilter {
+            exit_status: ExitFilter::Failure,
+            ..Default::default()
+        };
+        assert_eq!(db.select_where(&filter).unwrap().len(), 3);
+        assert!(!filter.is_empty());
+
+        assert_eq!("failure".parse::<ExitFilter>(), Ok(ExitFilter::Failure));
+        assert_eq!(
+            " 1, 127".parse::<ExitFilter>(),
+            Ok(ExitFilter::Codes(vec![1, 127]))
+        );
+        assert!("sometimes".parse::<ExitFilter>().is_err());
+        assert_eq!(ExitFilter::Codes(vec![1, 127]).to_string(), "1,127");
+    }
+
+    #[test]
+    fn test_cwd_under() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        new_history_item_in(&mut db, "make", "/home/ellie/src/rsq").unwrap();
+        new_history_item_in(&mut db, "cargo build", "/home/ellie/src/rsq/src").unwrap();
+        new_history_item_in(&mut db, "ls", "/home/ellie/src/rsq-old").unwrap();
+        new_history_item_in(&mut db, "pwd", "/home/ellie/src").unwrap();
+
+        let under = |dir: &str| {
+            let options = SearchOptions {
+                cwd_under: Some(dir.to_string()),
+                ..Default::default()
+            };
+            db.search_ranked("", &options)
+                .unwrap()
+                .into_iter()
+                .map(|hit| hit.item.command)
+                .sorted()
+                .collect_vec()
+        };
+
+        assert_eq!(under("/home/ellie/src/rsq"), vec!["cargo build", "make"]);
+        assert_eq!(under("/home/ellie/src/rsq/"), vec!["cargo build", "make"]);
+        assert_eq!(
+            under("/home/ellie//src/./x/../rsq"),
+            vec!["cargo build", "make"]
+        );
+        assert_eq!(under("/home/ellie/src").len(), 4);
+        assert_eq!(under("/").len(), 4);
+        assert!(under("/home/ellie/sr").is_empty());
+
+        let filter = HistoryFilter {
+            cwd_under: Some("/home/ellie/src/rsq".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(db.select_where(&filter).unwrap().len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cwd_under_symlink() {
+        let real = std::env::temp_dir().join(format!("rsq-real-{}", std::process::id()));
+        let link = std::env::temp_dir().join(format!("rsq-link-{}", std::process::id()));
+        let _ = std::fs::remove_file(&link);
+        std::fs::create_dir_all(&real).unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+        let real_dir = std::fs::canonicalize(&real).unwrap();
+
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        let cwd = real_dir.join("sub");
+        new_history_item_in(&mut db, "make", &cwd.to_string_lossy()).unwrap();
+
+        let forms = cwd_forms(&link.to_string_lossy());
+        assert_eq!(forms.len(), 2);
+        assert_eq!(forms[1], real_dir.to_string_lossy());
+
+        let filter = HistoryFilter {
+            cwd_under: Some(link.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        assert_eq!(db.select_where(&filter).unwrap().len(), 1);
+
+        std::fs::remove_file(&link).unwrap();
+        std::fs::remove_dir(&real).unwrap();
+    }
+
+    #[test]
+    fn test_suggest() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        let hours = chrono::Duration::hours;
+        new_history_item_ctx(&mut db, "git commit -m wip", "/home/ellie", 1, hours(30)).unwrap();
+        new_history_item_ctx(&mut db, "git commit -m wip", "/home/ellie", 1, hours(29)).unwrap();
+        new_history_item_ctx(&mut db, "git checkout main", "/home/ellie/src", 2, hours(2)).unwrap();
+        new_history_item_exit(&mut db, "git clean -fdx", 1, hours(1)).unwrap();
+        new_history_item_exit(&mut db, "git clean -fdx", 1, hours(0)).unwrap();
+
+        let suggest_with = |prefix: &str, cwd: Option<&str>, weights: &ContextWeights| {
+            let context = SearchContext {
+                cwd: cwd.map(|c| c.to_string()),
+                ..Default::default()
+            };
+            db.suggest(prefix, &context, weights).unwrap()
+        };
+        let suggest =
+            |prefix: &str, cwd: Option<&str>| suggest_with(prefix, cwd, &ContextWeights::default());
+
+        // run more often, and `git clean` always failed
+        assert_eq!(suggest("git c", None).unwrap(), "git commit -m wip");
+        // run in this directory
+        assert_eq!(
+            suggest("git c", Some("/home/ellie/src")).unwrap(),
+            "git checkout main"
+        );
+        // failed every time, but there is nothing else
+        assert_eq!(suggest("git cl", None), Some("git clean -fdx".to_string()));
+        // a command typed out in full is still suggested
+        assert_eq!(
+            suggest("git commit -m wip", None),
+            Some("git commit -m wip".to_string())
+        );
+        assert_eq!(suggest("", None), None);
+        assert_eq!(suggest("cargo", None), None);
+
+        // without the cwd boost the command run more often wins again
+        let weights = ContextWeights {
+            cwd: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            suggest_with("git c", Some("/home/ellie/src"), &weights).unwrap(),
+            "git commit -m wip"
+        );
+
+        assert_eq!(prefix_upper_bound("git"), Some("giu".to_string()));
+        assert_eq!(prefix_upper_bound("a\u{10ffff}"), Some("b".to_string()));
+    }
+
+    #[test]
+    fn test_suggest_many_runs() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        // with more runs than are ranked, the older ones don't count
+        let now = chrono::Utc::now();
+        let runs = (0..SUGGEST_CANDIDATES as i64 + 1)
+            .map(|ago| {
+                let line = if ago < SUGGEST_CANDIDATES as i64 {
+                    "git fetch"
+                } else {
+                    "git gc"
+                };
+                HistoryItem::new(
+                    None,
+                    line.to_string(),
+                    "git".to_string(),
+                    None,
+                    "/home/ellie".to_string(),
+                    0,
+                    0,
+                    Some(3),
+                    now - chrono::Duration::days(3) - chrono::Duration::seconds(ago),
+                    1_000_000,
+                )
+            })
+            .collect_vec();
+        db.save_bulk(&runs).unwrap();
+        let suggest = |prefix: &str| {
+            db.suggest(
+                prefix,
+                &SearchContext::default(),
+                &ContextWeights::default(),
+            )
+            .unwrap()
+        };
+        assert_eq!(suggest("git").unwrap(), "git fetch");
+        assert_eq!(suggest("git g").unwrap(), "git gc");
+    }
+
+    #[test]
+    fn test_predict_next() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        let minutes = chrono::Duration::minutes;
+        let sequence = [
+            ("cargo fmt", 1, 10),
+            ("cargo clippy", 1, 9),
+            ("git commit", 1, 8),
+            ("cargo fmt", 2, 7),
+            ("cargo clippy", 2, 6),
+            ("cargo fmt", 1, 5),
+            ("cargo test", 1, 4),
+        ];
+        for (cmd, session, ago) in sequence.iter() {
+            new_history_item_ctx(&mut db, cmd, "/home/ellie", *session, minutes(*ago)).unwrap();
+        }
+
+        let predictions = db.predict_next("cargo fmt").unwrap();
+        let commands = predictions
+            .iter()
+            .map(|p| p.command_line.as_str())
+            .collect_vec();
+        assert_eq!(commands, vec!["cargo clippy", "cargo test"]);
+        assert_eq!(predictions[0].count, 2);
+        assert!((predictions[0].probability - 2.0 / 3.0).abs() < 1e-9);
+
+        assert_eq!(
+            db.predict_next("cargo clippy").unwrap()[0].command_line,
+            "git commit"
+        );
+        assert!(db.predict_next("cargo test").unwrap().is_empty());
+
+        // a database that had history before transitions were kept
+        let path = temp_db_path("transitions");
+        {
+            let mut old = Sqlite::new(&path, SqlLogMode::Disabled).unwrap();
+            for (cmd, session, ago) in sequence.iter() {
+                new_history_item_ctx(&mut old, cmd, "/home/ellie", *session, minutes(*ago))
+                    .unwrap();
+            }
+            old.execute_batch(
+                "drop trigger trg_history_line_transition; drop table command_line_transitions",
+            )
+            .unwrap();
+        }
+        // read only it can't be counted, so it's read from the history
+        let read_only = Sqlite::open(&path, SqlLogMode::Disabled, OpenMode::ReadOnly).unwrap();
+        assert_eq!(read_only.predict_next("cargo fmt").unwrap(), predictions);
+        drop(read_only);
+        let reopened = Sqlite::new(&path, SqlLogMode::Disabled).unwrap();
+        assert_eq!(reopened.predict_next("cargo fmt").unwrap(), predictions);
+        drop(reopened);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_predict_next_command_lines() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        let minutes = chrono::Duration::minutes;
+        // importers keep the program name in `command`
+        for (line, ago) in [("cargo fmt", 3), ("cargo clippy", 2), ("git push", 1)] {
+            let (command, params) = line.split_once(' ').unwrap();
+            let history = HistoryItem::new(
+                None,
+                line.to_string(),
+                command.to_string(),
+                Some(params.to_string()),
+                "/home/ellie".to_string(),
+                0,
+                0,
+                Some(1),
+                chrono::Utc::now() - minutes(ago),
+                1,
+            );
+            db.save(&history).unwrap();
+        }
+
+        let next = |line: &str| {
+            db.predict_next(line)
+                .unwrap()
+                .into_iter()
+                .map(|p| p.command_line)
+                .collect_vec()
+        };
+        assert_eq!(next("cargo fmt"), vec!["cargo clippy"]);
+        assert_eq!(next("cargo clippy"), vec!["git push"]);
+        assert!(next("cargo").is_empty());
+    }
+
+    #[test]
+    fn test_unique_keys() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        let rows = [
+            ("git status", "/src/rsq", 40),
+            ("git push", "/src/rsq", 30),
+            ("git status", "/src/infra", 20),
+            ("git status", "/src/rsq", 10),
+        ];
+        for (line, cwd, ago) in rows.iter() {
+            let (command, params) = line.split_once(' ').unwrap();
+            let history = HistoryItem::new(
+                None,
+                line.to_string(),
+                command.to_string(),
+                Some(params.to_string()),
+                cwd.to_string(),
+                0,
+                0,
+                Some(1),
+                chrono::Utc::now() - chrono::Duration::minutes(*ago),
+                1,
+            );
+            db.save(&history).unwrap();
+        }
+
+        let listed = |key| {
+            db.list_unique(None, key)
+                .unwrap()
+                .iter()
+                .map(|h| (h.command_line.clone(), h.cwd.clone()))
+                .collect_vec()
+        };
+        let pair = |line: &str, cwd: &str| (line.to_string(), cwd.to_string());
+        assert_eq!(
+            listed(UniqueKey::Command),
+            vec![pair("git status", "/src/rsq")]
+        );
+        assert_eq!(
+            listed(UniqueKey::CommandLine),
+            vec![pair("git status", "/src/rsq"), pair("git push", "/src/rsq")]
+        );
+        assert_eq!(
+            listed(UniqueKey::CommandLineCwd),
+            vec![
+                pair("git status", "/src/rsq"),
+                pair("git status", "/src/infra"),
+                pair("git push", "/src/rsq"),
+            ]
+        );
+        assert_eq!(db.list(None, true).unwrap().len(), 1);
+
+        for key in [
+            UniqueKey::Command,
+            UniqueKey::CommandLine,
+            UniqueKey::CommandLineCwd,
+        ] {
+            let options = SearchOptions {
+                mode: SearchMode::Prefix,
+                field: SearchField::CommandLine,
+                unique_by: key,
+                ..Default::default()
+            };
+            let hits = db.search_ranked("git", &options).unwrap();
+            let searched = hits
+                .iter()
+                .map(|hit| (hit.item.command_line.clone(), hit.item.cwd.clone()))
+                .collect_vec();
+            assert_eq!(searched, listed(key));
+
+            // the SQL and the Rust helpers agree on what's the same
+            let all = db.list(None, false).unwrap();
+            let unique: HashSet<_> = all.iter().map(|h| h.keyed(key)).collect();
+            assert_eq!(unique.len(), searched.len());
+        }
+
+        // runs of the same command at the same time are still one command,
+        // the one saved last
+        let now = chrono::Utc::now();
+        for (line, cwd) in [("make build", "/src/rsq"), ("make test", "/src/infra")] {
+            let (command, params) = line.split_once(' ').unwrap();
+            let history = HistoryItem::new(
+                None,
+                line.to_string(),
+                command.to_string(),
+                Some(params.to_string()),
+                cwd.to_string(),
+                0,
+                0,
+                Some(1),
+                now,
+                1,
+            );
+            db.save(&history).unwrap();
+        }
+        let newest = db.list_unique(None, UniqueKey::Command).unwrap();
+        assert_eq!(newest.len(), 2);
+        assert_eq!(newest[0].command_line, "make test");
+        let options = SearchOptions {
+            mode: SearchMode::Prefix,
+            field: SearchField::CommandLine,
+            ranking: Ranking::Frecency(FrecencyWeights::default()),
+            ..Default::default()
+        };
+        let hits = db.search_ranked("make", &options).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].item.command_line, "make test");
+    }
+
+    #[test]
+    fn test_typo_search() {
+        let path = temp_db_path("typo");
+        let mut db = Sqlite::new(&path, SqlLogMode::Disabled).unwrap();
+        let lines = [
+            "kubectl get pods",
+            "kubectl get pods --all-namespaces",
+            "kubectl get services",
+            "git status",
+            "Git Stash Pop",
+        ];
+        for (ago, line) in lines.iter().enumerate() {
+            new_history_item_at(&mut db, line, chrono::Duration::minutes(ago as i64 + 1), 1)
+                .unwrap();
+        }
+        let find = |db: &Sqlite, query: &str| {
+            let options = SearchOptions {
+                mode: SearchMode::Typo,
+                ..Default::default()
+            };
+            db.search_ranked(query, &options)
+                .unwrap()
+                .into_iter()
+                .map(|hit| hit.item.command_line)
+                .collect_vec()
+        };
+
+        // closest first, then the most alike of the whole line
+        let pods = vec!["kubectl get pods", "kubectl get pods --all-namespaces"];
+        assert_eq!(find(&db, "kubctl get pods"), pods);
+        assert_eq!(find(&db, "kubectl get pods"), pods);
+        assert_eq!(find(&db, "gti status"), vec!["git status"]);
+        assert_eq!(find(&db, "stash"), vec!["Git Stash Pop"]);
+        assert!(find(&db, "docker compose up").is_empty());
+
+        let hits = db
+            .search_ranked(
+                "kubctl get srevices",
+                &SearchOptions {
+                    mode: SearchMode::Typo,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].field, SearchField::CommandLine);
+        assert_eq!(hits[0].matches, vec![0..20]);
+
+        // lines saved before the trigrams were kept are indexed on open
+        db.execute_batch("drop table command_trigrams").unwrap();
+        drop(db);
+        let db = Sqlite::new(&path, SqlLogMode::Disabled).unwrap();
+        let indexed: i64 = db
+            .query_row(
+                "select count(distinct command_line) from command_trigrams",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(indexed, lines.len() as i64);
+        assert_eq!(find(&db, "kubctl get pods"), pods);
+
+        // updated lines are indexed as well
+        let mut item = db.load("4").unwrap();
+        item.command_line = "terraform plan".to_string();
+        db.update(&item).unwrap();
+        assert_eq!(find(&db, "terrafrom plan"), vec!["terraform plan"]);
+
+        // and the trigrams of a line go with its last row
+        let indexed = |db: &Sqlite, line: &str| -> i64 {
+            db.query_row(
+                "select count(1) from command_trigrams where command_line = ?1",
+                [line],
+                |r| r.get(0),
+            )
+            .unwrap()
+        };
+        assert_eq!(indexed(&db, "git status"), 0);
+        let mut db = db;
+        new_history_item(&mut db, "kubectl get pods").unwrap();
+        db.delete_history_item(1).unwrap();
+        assert!(indexed(&db, "kubectl get pods") > 0);
+        let id = db.last().unwrap().history_id.unwrap();
+        db.delete_history_item(id).unwrap();
+        assert_eq!(indexed(&db, "kubectl get pods"), 0);
+
+        // a query too short for trigrams has to be in the line as it is
+        assert_eq!(
+            find(&db, "Po"),
+            vec!["kubectl get pods --all-namespaces", "Git Stash Pop"]
+        );
+        drop(db);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_metadata() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        let mut history = HistoryItem::new(
+            None,
+            "vim src/main.rs".to_string(),
+            "vim".to_string(),
+            Some("src/main.rs".to_string()),
+            "/src/rsq".to_string(),
+            -1,
+            0,
+            Some(1),
+            chrono::Utc::now(),
+            1,
+        );
+        history.metadata.insert(
+            "path".to_string(),
+            vec!["src/main.rs".to_string(), "Cargo.toml".to_string()],
+        );
+        history
+            .metadata
+            .insert("hostname".to_string(), vec!["laptop".to_string()]);
+        db.save(&history).unwrap();
+        new_history_item(&mut db, "ls").unwrap();
+
+        let loaded = db.load("1").unwrap();
+        assert_eq!(loaded.metadata, history.metadata);
+        assert!(db.load("2").unwrap().metadata.is_empty());
+
+        db.delete_history_item(1).unwrap();
+        assert!(db.metadata(1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_search_field_finds_older_runs() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        let hours = chrono::Duration::hours;
+        new_history_item_ctx(&mut db, "make", "/src/infra", 1, hours(2)).unwrap();
+        new_history_item_ctx(&mut db, "make", "/tmp", 1, hours(1)).unwrap();
+
+        // the newest `make` ran in /tmp, but an older one matches
+        for mode in [SearchMode::Prefix, SearchMode::Boolean] {
+            let options = SearchOptions {
+                mode,
+                field: SearchField::Cwd,
+                ..Default::default()
+            };
+            let hits = db.search_ranked("/src/infra", &options).unwrap();
+            assert_eq!(hits.len(), 1, "{:?}", mode);
+            assert_eq!(hits[0].item.cwd, "/src/infra");
+        }
+    }
+
+    #[test]
+    fn test_matches_in_folded_text() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        new_history_item(&mut db, "cd straße").unwrap();
+        new_history_item(&mut db, "cd İstanbul").unwrap();
+
+        let find = |mode: SearchMode, query: &str, line: &str| {
+            let options = SearchOptions {
+                mode,
+                case: CaseMode::Insensitive,
+                ..Default::default()
+            };
+            let hits = db.search_ranked(query, &options).unwrap();
+            let hit = hits.iter().find(|h| h.item.command_line == line).unwrap();
+            hit.matches.clone()
+        };
+
+        // both `s` of the folded `ß` are the same two bytes of the text
+        let line = "cd straße";
+        assert_eq!(find(SearchMode::FullText, "s", line), vec![3..4, 7..9]);
+        assert_eq!(find(SearchMode::FullText, "ss", line), vec![7..9]);
+        assert_eq!(find(SearchMode::Fuzzy, "asse", line), vec![6..10]);
+        // `İ` folds to `i` and a combining dot, three bytes for its two
+        let line = "cd İstanbul";
+        assert_eq!(find(SearchMode::FullText, "İst", line), vec![3..7]);
+        assert_eq!(find(SearchMode::FullText, "stan", line), vec![5..9]);
+        assert_eq!(find(SearchMode::Prefix, "CD İ", line), vec![0..5]);
+    }
+
+    #[test]
+    fn test_match_quality_weighs_frecency() {
+        let mut db = Sqlite::new(":memory:", SqlLogMode::Disabled).unwrap();
+        let hours = chrono::Duration::hours;
+        new_history_item_at(&mut db, "kubectl get", hours(1), 1).unwrap();
+        // run more often, but a typo away from the query
+        new_history_item_at(&mut db, "kubctl get", hours(1), 2).unwrap();
+        new_history_item_at(&mut db, "git status", hours(1), 1).unwrap();
+        new_history_item_at(&mut db, "grep -rn test .", hours(1), 2).unwrap();
+
+        let first = |mode: SearchMode, query: &str| {
+            let options = SearchOptions {
+                mode,
+                field: SearchField::CommandLine,
+                ranking: Ranking::Frecency(FrecencyWeights::default()),
+                ..Default::default()
+            };
+            let hits = db.search_ranked(query, &options).unwrap();
+            assert_eq!(hits.len(), 2);
+            hits[0].item.command_line.clone()
+        };
+        assert_eq!(first(SearchMode::Typo, "kubectl"), "kubectl get");
+        assert_eq!(first(SearchMode::Fuzzy, "gst"), "git status");
     }
 }
@@ -0,0 +1,260 @@
+// fzf/skim style fuzzy matching
+//
+// Every character of the pattern has to appear in the text, in order. Of all
+// the ways that can happen, the one with the best score is chosen: matches
+// are worth more when they start a word, follow a path separator or continue
+// a run of matched characters, and gaps between matched characters cost
+// points. Scoring constants follow fzf's.
+//
+// Without case sensitivity both the pattern and the text are compared case
+// folded, the same way as `fold_case` folds them for the sql prefilter.
+
+use crate::database::{fold_case, fold_char};
+
+const SCORE_MATCH: i64 = 16;
+const SCORE_GAP_START: i64 = -3;
+const SCORE_GAP_EXTENSION: i64 = -1;
+
+const BONUS_BOUNDARY_WHITE: i64 = 10;
+const BONUS_BOUNDARY_PATH: i64 = 9;
+const BONUS_BOUNDARY_DELIMITER: i64 = 8;
+const BONUS_NON_WORD: i64 = 8;
+const BONUS_CAMEL: i64 = 7;
+const BONUS_CONSECUTIVE: i64 = 4;
+const BONUS_FIRST_CHAR_MULTIPLIER: i64 = 2;
+
+/// A fuzzy match of a pattern in some text
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuzzyMatch {
+    /// Higher is better
+    pub score: i64,
+    /// Byte offset in the text of every matched character, in order. A
+    /// character that folds to several, like `ß`, is there once.
+    pub positions: Vec<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum CharClass {
+    White,
+    Path,
+    Delimiter,
+    NonWord,
+    Lower,
+    Upper,
+    Number,
+}
+
+fn char_class(c: char) -> CharClass {
+    if c.is_whitespace() {
+        CharClass::White
+    } else if c == '/' || c == '\\' {
+        CharClass::Path
+    } else if ",;:|-_.=".contains(c) {
+        CharClass::Delimiter
+    } else if c.is_lowercase() {
+        CharClass::Lower
+    } else if c.is_uppercase() {
+        CharClass::Upper
+    } else if c.is_numeric() {
+        CharClass::Number
+    } else if c.is_alphabetic() {
+        // scripts without case
+        CharClass::Lower
+    } else {
+        CharClass::NonWord
+    }
+}
+
+fn bonus(prev: CharClass, class: CharClass) -> i64 {
+    match (prev, class) {
+        (_, CharClass::White) | (_, CharClass::Path) | (_, CharClass::Delimiter) => BONUS_NON_WORD,
+        (_, CharClass::NonWord) => BONUS_NON_WORD,
+        (CharClass::White, _) => BONUS_BOUNDARY_WHITE,
+        (CharClass::Path, _) => BONUS_BOUNDARY_PATH,
+        (CharClass::Delimiter, _) | (CharClass::NonWord, _) => BONUS_BOUNDARY_DELIMITER,
+        (CharClass::Lower, CharClass::Upper) => BONUS_CAMEL,
+        (CharClass::Lower, CharClass::Number) | (CharClass::Upper, CharClass::Number) => {
+            BONUS_CAMEL
+        }
+        _ => 0,
+    }
+}
+
+/// Smart case: the match is case sensitive only when the pattern has an
+/// upper case character.
+pub fn fuzzy_match(pattern: &str, text: &str) -> Option<FuzzyMatch> {
+    let case_sensitive = pattern.chars().any(|c| c.is_uppercase());
+    fuzzy_match_with_case(pattern, text, case_sensitive)
+}
+
+pub fn fuzzy_match_with_case(
+    pattern: &str,
+    text: &str,
+    case_sensitive: bool,
+) -> Option<FuzzyMatch> {
+    let pattern: Vec<char> = match case_sensitive {
+        true => pattern.chars().collect(),
+        false => fold_case(pattern).chars().collect(),
+    };
+    // the characters compared, with the byte offset of the one in the text
+    // they came from and its bonus. Folding can turn one into several, of
+    // which only the first gets the bonus.
+    let mut chars: Vec<(usize, char)> = vec![];
+    let mut bonuses: Vec<i64> = vec![];
+    let mut prev = CharClass::White;
+    let mut folded = String::new();
+    for (offset, c) in text.char_indices() {
+        let class = char_class(c);
+        folded.clear();
+        match case_sensitive {
+            true => folded.push(c),
+            false => fold_char(c, &mut folded),
+        }
+        for (k, f) in folded.chars().enumerate() {
+            chars.push((offset, f));
+            bonuses.push(if k == 0 { bonus(prev, class) } else { 0 });
+        }
+        prev = class;
+    }
+    let text = chars;
+    let (m, n) = (pattern.len(), text.len());
+
+    if m == 0 {
+        return Some(FuzzyMatch {
+            score: 0,
+            positions: vec![],
+        });
+    }
+    if m > n {
+        return None;
+    }
+
+    // score[i][j]: best score with pattern[i] matched at text[j]
+    // run[i][j]: bonus of the consecutive run that path is in
+    // from[i][j]: where pattern[i - 1] was matched on that path
+    const NONE: i64 = i64::MIN / 2;
+    let mut score = vec![vec![NONE; n]; m];
+    let mut run = vec![vec![0i64; n]; m];
+    let mut from = vec![vec![0usize; n]; m];
+
+    for (i, p) in pattern.iter().enumerate() {
+        // best score of pattern[i - 1] matched at k <= j - 2, including the
+        // gap penalty up to j
+        let mut gapped = NONE;
+        let mut gapped_from = 0;
+
+        for j in i..n {
+            if i > 0 && j >= 2 && score[i - 1][j - 2] > NONE {
+                let opened = score[i - 1][j - 2] + SCORE_GAP_START;
+                if opened >= gapped + SCORE_GAP_EXTENSION {
+                    gapped = opened;
+                    gapped_from = j - 2;
+                } else {
+                    gapped += SCORE_GAP_EXTENSION;
+                }
+            } else if gapped > NONE {
+                gapped += SCORE_GAP_EXTENSION;
+            }
+
+            if *p != text[j].1 {
+                continue;
+            }
+
+            if i == 0 {
+                score[i][j] = SCORE_MATCH + bonuses[j] * BONUS_FIRST_CHAR_MULTIPLIER;
+                run[i][j] = bonuses[j];
+                continue;
+            }
+
+            let mut best = NONE;
+            if gapped > NONE {
+                best = gapped + SCORE_MATCH + bonuses[j];
+                run[i][j] = bonuses[j];
+                from[i][j] = gapped_from;
+            }
+            if j >= 1 && score[i - 1][j - 1] > NONE {
+                let b = run[i - 1][j - 1].max(BONUS_CONSECUTIVE).max(bonuses[j]);
+                let consecutive = score[i - 1][j - 1] + SCORE_MATCH + b;
+                if consecutive >= best {
+                    best = consecutive;
+                    run[i][j] = b;
+                    from[i][j] = j - 1;
+                }
+            }
+            score[i][j] = best;
+        }
+    }
+
+    let (mut j, best) = score[m - 1]
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| **s > NONE)
+        .max_by_key(|(j, s)| (**s, std::cmp::Reverse(*j)))
+        .map(|(j, s)| (j, *s))?;
+
+    let mut positions = vec![0; m];
+    for i in (0..m).rev() {
+        positions[i] = text[j].0;
+        j = from[i][j];
+    }
+    positions.dedup();
+
+    Some(FuzzyMatch {
+        score: best,
+        positions,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_subsequence() {
+        assert!(fuzzy_match("l/h/", "ls /home/ellie").is_some());
+        assert!(fuzzy_match("/hmoe/", "ls /home/ellie").is_none());
+        assert!(fuzzy_match("ellie/home", "ls /home/ellie").is_none());
+        assert_eq!(fuzzy_match("", "ls").unwrap().score, 0);
+        assert!(fuzzy_match("lsx", "ls").is_none());
+    }
+
+    #[test]
+    fn test_positions_are_byte_offsets() {
+        let m = fuzzy_match("ée", "café crème").unwrap();
+        assert_eq!(m.positions, vec![3, 11]);
+        assert_eq!(&"café crème"[m.positions[1]..m.positions[1] + 1], "e");
+    }
+
+    #[test]
+    fn test_prefers_tight_and_boundary_matches() {
+        let tight = fuzzy_match("cargo", "cargo build").unwrap();
+        let scattered = fuzzy_match("cargo", "cat ./args/go.txt").unwrap();
+        assert!(tight.score > scattered.score);
+        assert_eq!(tight.positions, vec![0, 1, 2, 3, 4]);
+
+        // the `b` at the start of `build` beats the one inside `lib`
+        let m = fuzzy_match("b", "lib build").unwrap();
+        assert_eq!(m.positions, vec![4]);
+
+        // path separators count as word boundaries
+        let path = fuzzy_match("src", "cd ~/src").unwrap();
+        let inner = fuzzy_match("src", "cd ~/rsrc").unwrap();
+        assert!(path.score > inner.score);
+    }
+
+    #[test]
+    fn test_smart_case() {
+        assert!(fuzzy_match("make", "Makefile").is_some());
+        assert!(fuzzy_match("Make", "makefile").is_none());
+        assert!(fuzzy_match_with_case("make", "Makefile", true).is_none());
+    }
+
+    #[test]
+    fn test_full_case_folding() {
+        // `ß` folds to `ss`, as it does in the sql prefilter
+        let m = fuzzy_match("strasse", "cd Straße").unwrap();
+        assert_eq!(m.positions, vec![3, 4, 5, 6, 7, 9]);
+        assert!(fuzzy_match("sss", "cd straße").is_some());
+        assert!(fuzzy_match_with_case("ss", "straße", true).is_none());
+    }
+}
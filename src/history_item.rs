@@ -1,20 +1,30 @@
 use chrono::Utc;
 use core::hash::{Hash, Hasher};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::process;
 
-#[derive(Debug, Clone, Ord, PartialOrd)]
+/// A single row of history.
+///
+/// Serialised field names match the `history_items` column names, and the
+/// timestamp is written as an RFC 3339 string (e.g. `2021-07-21T12:34:56.789Z`),
+/// so the format stays stable for JSON, config files and IPC.
+#[derive(Debug, Clone, Ord, PartialOrd, Serialize, Deserialize)]
 pub struct HistoryItem {
     /// Primary Key, Unique Id
+    #[serde(default)]
     pub history_id: Option<i64>,
     /// Entire command line
     pub command_line: String,
     /// Command part of the command line
     pub command: String,
     /// Parameters part of the command line
+    #[serde(default)]
     pub command_params: Option<String>,
     /// Current working directory
     pub cwd: String,
-    /// How long it took to run the command
+    /// How long it took to run the command in milliseconds, negative when
+    /// unknown
     pub duration: i64,
     /// The exit status / return status of the command
     pub exit_status: i64,
@@ -24,9 +34,19 @@ pub struct HistoryItem {
     pub timestamp: chrono::DateTime<Utc>,
     /// How many times was this command ran
     pub run_count: i64,
+    /// Which attached database the row came from, `None` when nothing is
+    /// attached
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// What else is known about the command, e.g. the `path`s fish
+    /// recorded for it, in the order they were added. Kept by `save`, read
+    /// by `load` and `Database::metadata` only.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, Vec<String>>,
 }
 
 impl HistoryItem {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         history_id: Option<i64>,
         command_line: String,
@@ -52,8 +72,111 @@ impl HistoryItem {
             session_id,
             timestamp,
             run_count,
+            source: None,
+            metadata: BTreeMap::new(),
         }
     }
+
+    /// Whether the two count as the same history when deduplicating by `key`
+    pub fn same_as(&self, other: &Self, key: UniqueKey) -> bool {
+        match key {
+            UniqueKey::Command => self.command == other.command,
+            UniqueKey::CommandLine => self.command_line == other.command_line,
+            UniqueKey::CommandLineCwd => {
+                self.command_line == other.command_line && self.cwd == other.cwd
+            }
+        }
+    }
+
+    /// Hash the columns of `key`, consistent with `same_as`
+    pub fn hash_by<H: Hasher>(&self, key: UniqueKey, state: &mut H) {
+        match key {
+            UniqueKey::Command => self.command.hash(state),
+            UniqueKey::CommandLine => self.command_line.hash(state),
+            UniqueKey::CommandLineCwd => {
+                self.command_line.hash(state);
+                self.cwd.hash(state);
+            }
+        }
+    }
+
+    /// This item compared and hashed by `key`, e.g. to collect unique
+    /// history in a `HashSet`
+    pub fn keyed(&self, key: UniqueKey) -> Keyed<'_> {
+        Keyed { item: self, key }
+    }
+}
+
+/// Which columns make two rows the same history when listing or searching
+/// for unique history
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UniqueKey {
+    #[default]
+    Command,
+    CommandLine,
+    /// The same command line run in another directory is different history
+    CommandLineCwd,
+}
+
+impl UniqueKey {
+    pub fn variants() -> [&'static str; 3] {
+        ["command", "command_line", "command_line_cwd"]
+    }
+
+    /// The `history_items` columns of the key
+    pub fn columns(&self) -> &'static [&'static str] {
+        match self {
+            UniqueKey::Command => &["command"],
+            UniqueKey::CommandLine => &["command_line"],
+            UniqueKey::CommandLineCwd => &["command_line", "cwd"],
+        }
+    }
+}
+
+impl core::str::FromStr for UniqueKey {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "command" => Ok(Self::Command),
+            "command_line" => Ok(Self::CommandLine),
+            "command_line_cwd" => Ok(Self::CommandLineCwd),
+            _ => Err("Could not parse UniqueKey"),
+        }
+    }
+}
+
+impl std::fmt::Display for UniqueKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UniqueKey::Command => write!(f, "command"),
+            UniqueKey::CommandLine => write!(f, "command_line"),
+            UniqueKey::CommandLineCwd => write!(f, "command_line_cwd"),
+        }
+    }
+}
+
+/// A `HistoryItem` that's equal to and hashes like the items that are the
+/// same history by `key`
+#[derive(Clone, Copy, Debug)]
+pub struct Keyed<'a> {
+    pub item: &'a HistoryItem,
+    pub key: UniqueKey,
+}
+
+impl PartialEq for Keyed<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key && self.item.same_as(other.item, self.key)
+    }
+}
+
+impl Eq for Keyed<'_> {}
+
+impl Hash for Keyed<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.item.hash_by(self.key, state);
+    }
 }
 
 impl PartialEq for HistoryItem {
@@ -61,8 +184,9 @@ impl PartialEq for HistoryItem {
     // anything else
     // obviously this does not refer to the *same* item of history, but when
     // we only render the command, it looks the same
+    // use `keyed` to compare by another `UniqueKey`
     fn eq(&self, other: &Self) -> bool {
-        self.command == other.command
+        self.same_as(other, UniqueKey::Command)
     }
 }
 
@@ -70,6 +194,64 @@ impl Eq for HistoryItem {}
 
 impl Hash for HistoryItem {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.command.hash(state);
+        self.hash_by(UniqueKey::Command, state);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_serde_round_trip() {
+        let item = HistoryItem::new(
+            Some(7),
+            "git commit -m wip".to_string(),
+            "git".to_string(),
+            Some("commit -m wip".to_string()),
+            "/home/ellie".to_string(),
+            12,
+            0,
+            Some(42),
+            Utc.timestamp_nanos(1_626_813_332_831_940_400),
+            3,
+        );
+
+        let json = serde_json::to_value(&item).unwrap();
+        assert_eq!(json["history_id"], 7);
+        assert_eq!(json["command_line"], "git commit -m wip");
+        assert_eq!(json["timestamp"], "2021-07-20T20:35:32.831940400Z");
+
+        let back: HistoryItem = serde_json::from_value(json).unwrap();
+        assert_eq!(back.history_id, item.history_id);
+        assert_eq!(back.command_line, item.command_line);
+        assert_eq!(back.command_params, item.command_params);
+        assert_eq!(back.session_id, item.session_id);
+        assert_eq!(back.timestamp, item.timestamp);
+    }
+
+    #[test]
+    fn test_deserialize_without_optional_fields() {
+        let item: HistoryItem = serde_json::from_str(
+            r#"{
+                "command_line": "ls",
+                "command": "ls",
+                "cwd": "/tmp",
+                "duration": 0,
+                "exit_status": 0,
+                "session_id": 1,
+                "timestamp": "2021-07-21T00:00:00+02:00",
+                "run_count": 1
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(item.history_id, None);
+        assert_eq!(item.command_params, None);
+        assert_eq!(
+            item.timestamp,
+            Utc.with_ymd_and_hms(2021, 7, 20, 22, 0, 0).unwrap()
+        );
     }
 }
@@ -0,0 +1,869 @@
+// Importers for the history other shells and tools keep
+//
+// Every importer reads a whole history into `HistoryItem`s, oldest first,
+// ready for `Database::save_bulk`. Fields the source doesn't record are
+// left unknown the way `HistoryItem` describes, e.g. a negative duration.
+
+use crate::history_item::HistoryItem;
+use anyhow::{bail, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::{Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// The exit status of commands whose history doesn't say how they exited
+pub const UNKNOWN_EXIT_STATUS: i64 = -1;
+
+/// Which kind of history file to import
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    /// Nushell's plaintext history, one command per line
+    #[default]
+    Nushell,
+    /// Reedline's SQLite history, which nushell keeps with
+    /// `history.file_format = "sqlite"`
+    Reedline,
+    /// `~/.bash_history`, with or without `HISTTIMEFORMAT` timestamps
+    Bash,
+    /// `~/.zsh_history`, with or without `EXTENDED_HISTORY`
+    Zsh,
+    /// `~/.local/share/fish/fish_history`
+    Fish,
+    /// Atuin's `~/.local/share/atuin/history.db`
+    Atuin,
+    /// McFly's `history.db`, in `~/.local/share/mcfly` or `~/.mcfly`
+    McFly,
+}
+
+impl ImportFormat {
+    pub fn variants() -> [&'static str; 7] {
+        [
+            "nushell", "reedline", "bash", "zsh", "fish", "atuin", "mcfly",
+        ]
+    }
+}
+
+impl core::str::FromStr for ImportFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nushell" => Ok(Self::Nushell),
+            "reedline" => Ok(Self::Reedline),
+            "bash" => Ok(Self::Bash),
+            "zsh" => Ok(Self::Zsh),
+            "fish" => Ok(Self::Fish),
+            "atuin" => Ok(Self::Atuin),
+            "mcfly" => Ok(Self::McFly),
+            _ => Err("Could not parse ImportFormat"),
+        }
+    }
+}
+
+impl std::fmt::Display for ImportFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportFormat::Nushell => write!(f, "nushell"),
+            ImportFormat::Reedline => write!(f, "reedline"),
+            ImportFormat::Bash => write!(f, "bash"),
+            ImportFormat::Zsh => write!(f, "zsh"),
+            ImportFormat::Fish => write!(f, "fish"),
+            ImportFormat::Atuin => write!(f, "atuin"),
+            ImportFormat::McFly => write!(f, "mcfly"),
+        }
+    }
+}
+
+/// Split a command line into the command and its parameters, if it has any
+pub fn split_command_line(command_line: &str) -> (String, Option<String>) {
+    let trimmed = command_line.trim_start();
+    match trimmed.split_once(char::is_whitespace) {
+        Some((command, params)) if !params.trim().is_empty() => {
+            (command.to_string(), Some(params.trim_start().to_string()))
+        }
+        Some((command, _)) => (command.to_string(), None),
+        None => (trimmed.to_string(), None),
+    }
+}
+
+/// A `HistoryItem` for an imported command line, with its command and
+/// parameters split off
+fn imported(
+    command_line: String,
+    cwd: String,
+    duration: i64,
+    exit_status: i64,
+    session_id: i64,
+    timestamp: chrono::DateTime<Utc>,
+) -> HistoryItem {
+    let (command, command_params) = split_command_line(&command_line);
+    HistoryItem::new(
+        None,
+        command_line,
+        command,
+        command_params,
+        cwd,
+        duration,
+        exit_status,
+        Some(session_id),
+        timestamp,
+        1,
+    )
+}
+
+/// Give commands whose history has no time for them one that keeps them in
+/// order: a millisecond after the command before them, or before the one
+/// after them when they come first. When nothing has a time, the commands
+/// are a second apart from the Unix epoch on, so that importing the same
+/// history again after the shell added to it gives the old commands the
+/// same times, and they are taken for duplicates rather than imported
+/// twice. Commands recorded in the same second, which is all some shells
+/// keep, are a millisecond apart as well, so that none of them is taken
+/// for a duplicate.
+pub fn fill_timestamps(times: &[Option<DateTime<Utc>>]) -> Vec<DateTime<Utc>> {
+    let ms = chrono::Duration::milliseconds;
+    let first_known = times.iter().position(Option::is_some);
+    let mut filled: Vec<DateTime<Utc>> = Vec::with_capacity(times.len());
+    for (idx, time) in times.iter().enumerate() {
+        let time = match (time, filled.last(), first_known) {
+            (Some(time), Some(&previous), _)
+                if *time <= previous && time.timestamp() == previous.timestamp() =>
+            {
+                previous + ms(1)
+            }
+            (Some(time), _, _) => *time,
+            (None, _, None) => Utc.timestamp_opt(idx as i64, 0).unwrap(),
+            (None, Some(&previous), _) => previous + ms(1),
+            (None, None, Some(first)) => times[first].unwrap() - ms((first - idx) as i64),
+        };
+        filled.push(time);
+    }
+    filled
+}
+
+/// Open another tool's SQLite database without writing to it
+fn open_read_only(path: &Path) -> Result<Connection> {
+    if !path.exists() {
+        bail!("{:?} does not exist", path);
+    }
+    Ok(Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?)
+}
+
+/// Read reedline's `history` table. It keeps start times in milliseconds
+/// and leaves every column but the command line null when it wasn't
+/// recorded. A missing start time is taken to be just after the command
+/// before it, so that the order of the history is kept. The `hostname` and
+/// the `more_info` the host application kept are kept as metadata.
+pub fn import_reedline(path: impl AsRef<Path>) -> Result<Vec<HistoryItem>> {
+    let conn = open_read_only(path.as_ref())?;
+    let mut stmt = conn.prepare(
+        "select command_line, start_timestamp, session_id, hostname, cwd, duration_ms,
+        exit_status, more_info
+        from history order by id",
+    )?;
+    let mut rows = stmt.query([])?;
+
+    let mut items: Vec<HistoryItem> = vec![];
+    let mut times = vec![];
+    while let Some(row) = rows.next()? {
+        times.push(match row.get::<_, Option<i64>>("start_timestamp")? {
+            Some(ms) => Utc.timestamp_millis_opt(ms).single(),
+            None => None,
+        });
+        let mut item = imported(
+            row.get("command_line")?,
+            row.get::<_, Option<String>>("cwd")?.unwrap_or_default(),
+            row.get::<_, Option<i64>>("duration_ms")?.unwrap_or(-1),
+            row.get::<_, Option<i64>>("exit_status")?
+                .unwrap_or(UNKNOWN_EXIT_STATUS),
+            row.get::<_, Option<i64>>("session_id")?.unwrap_or(0),
+            Utc::now(),
+        );
+        for key in ["hostname", "more_info"] {
+            if let Some(value) = row.get::<_, Option<String>>(key)? {
+                item.metadata.insert(key.to_string(), vec![value]);
+            }
+        }
+        items.push(item);
+    }
+    for (item, time) in items.iter_mut().zip(fill_timestamps(&times)) {
+        item.timestamp = time;
+    }
+    Ok(items)
+}
+
+/// Read bash history. With `HISTTIMEFORMAT` set, bash writes a `#<epoch>`
+/// line before every command, and everything up to the next one belongs
+/// to the command, so commands saved with `lithist` keep their newlines.
+/// Without them every line is a command, unless it ends in a `\`, which
+/// continues it on the next line. The last timed command goes on to the
+/// end of the file, unless a line without a time ends in a `\`: then bash
+/// wrote some of the history without `HISTTIMEFORMAT`, and the lines after
+/// the first one after the last timestamp may be commands of their own.
+/// Bash doesn't keep the cwd, duration or exit status.
+pub fn import_bash(path: impl AsRef<Path>) -> Result<Vec<HistoryItem>> {
+    let path = path.as_ref();
+    let text = String::from_utf8_lossy(&std::fs::read(path)?).into_owned();
+    let (lines, times): (Vec<_>, Vec<_>) = parse_bash(&text).into_iter().unzip();
+    Ok(lines
+        .into_iter()
+        .zip(fill_timestamps(&times))
+        .map(|(line, time)| imported(line, String::new(), -1, UNKNOWN_EXIT_STATUS, 0, time))
+        .collect())
+}
+
+/// A `#<epoch>` timestamp line of bash history
+fn bash_timestamp(line: &str) -> Option<DateTime<Utc>> {
+    let epoch = line.strip_prefix('#')?;
+    if epoch.is_empty() || !epoch.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Utc.timestamp_opt(epoch.parse().ok()?, 0).single()
+}
+
+/// The commands of bash history, with their time when it has one
+fn parse_bash(text: &str) -> Vec<(String, Option<DateTime<Utc>>)> {
+    let lines: Vec<&str> = text.lines().collect();
+    let continues = |line: &str| (line.len() - line.trim_end_matches('\\').len()) % 2 == 1;
+    let first_timestamp = lines.iter().position(|line| bash_timestamp(line).is_some());
+    let last_timestamp = lines
+        .iter()
+        .rposition(|line| bash_timestamp(line).is_some());
+    // a continued line outside of the timed commands means that bash also
+    // wrote without HISTTIMEFORMAT, which it may have done after the last
+    // timestamp, and then only the first line after it surely belongs to it
+    let timed_until = match (first_timestamp, last_timestamp) {
+        (Some(first), Some(last)) => {
+            let untimed = lines
+                .iter()
+                .enumerate()
+                .any(|(i, line)| (i < first || i > last + 1) && continues(line));
+            if untimed {
+                last
+            } else {
+                lines.len()
+            }
+        }
+        _ => 0,
+    };
+    let mut commands: Vec<(String, Option<DateTime<Utc>>)> = vec![];
+    // whether the next line belongs to the last command
+    let mut continued = false;
+    for (i, line) in lines.into_iter().enumerate() {
+        if let Some(time) = bash_timestamp(line) {
+            commands.push((String::new(), Some(time)));
+            continued = true;
+            continue;
+        }
+        match commands.last_mut() {
+            Some((last, _)) if continued => {
+                if !last.is_empty() {
+                    last.push('\n');
+                }
+                last.push_str(line);
+            }
+            _ => commands.push((line.to_string(), None)),
+        }
+        // a timed command goes on up to the next timestamp
+        continued =
+            (i < timed_until && commands.last().is_some_and(|c| c.1.is_some())) || continues(line);
+    }
+
+    // e.g. a timestamp without a command after it
+    commands.retain(|(command, _)| !command.trim().is_empty());
+    commands
+}
+
+/// Read zsh history. With `EXTENDED_HISTORY` every command starts with
+/// `: <start>:<elapsed>;`, the time it started and how many seconds it
+/// ran, otherwise commands are a second apart like in `import_bash`. A
+/// line ending in a `\` goes on with the next one, which is how zsh saves
+/// the newlines of multi-line commands. Zsh doesn't keep the cwd or exit
+/// status.
+pub fn import_zsh(path: impl AsRef<Path>) -> Result<Vec<HistoryItem>> {
+    let path = path.as_ref();
+    let text = String::from_utf8_lossy(&unmetafy(&std::fs::read(path)?)).into_owned();
+    let commands = parse_zsh(&text);
+    let times = commands.iter().map(|c| c.1).collect::<Vec<_>>();
+    Ok(commands
+        .into_iter()
+        .zip(fill_timestamps(&times))
+        .map(|((line, _, duration), time)| {
+            imported(line, String::new(), duration, UNKNOWN_EXIT_STATUS, 0, time)
+        })
+        .collect())
+}
+
+/// Zsh's marker for a byte it changed, so that it can't be mistaken for
+/// one of its internal tokens, by flipping bit 5 of the byte after it
+const ZSH_META: u8 = 0x83;
+
+/// Undo zsh's metafication of the bytes of its history file
+fn unmetafy(bytes: &[u8]) -> Vec<u8> {
+    let mut plain = Vec::with_capacity(bytes.len());
+    let mut bytes = bytes.iter();
+    while let Some(&b) = bytes.next() {
+        match b {
+            ZSH_META => {
+                if let Some(&next) = bytes.next() {
+                    plain.push(next ^ 0x20);
+                }
+            }
+            b => plain.push(b),
+        }
+    }
+    plain
+}
+
+/// The `: <start>:<elapsed>;` at the start of an extended history line,
+/// with the start time, the duration in milliseconds and the command
+fn zsh_extended(line: &str) -> Option<(DateTime<Utc>, i64, &str)> {
+    let (header, command) = line.strip_prefix(':')?.split_once(';')?;
+    let (start, elapsed) = header.trim_start().split_once(':')?;
+    let start = Utc.timestamp_opt(start.parse().ok()?, 0).single()?;
+    let elapsed: i64 = elapsed.parse().ok()?;
+    Some((start, elapsed * 1000, command))
+}
+
+/// The commands of zsh history, with their start time and duration when
+/// it has them
+fn parse_zsh(text: &str) -> Vec<(String, Option<DateTime<Utc>>, i64)> {
+    let mut commands: Vec<(String, Option<DateTime<Utc>>, i64)> = vec![];
+    let mut continued = false;
+    for line in text.lines() {
+        let (part, more) = match line.strip_suffix('\\') {
+            Some(part) => (part, true),
+            None => (line, false),
+        };
+        match commands.last_mut() {
+            Some((last, _, _)) if continued => {
+                last.push('\n');
+                last.push_str(part);
+            }
+            _ => commands.push(match zsh_extended(part) {
+                Some((start, duration, command)) => (command.to_string(), Some(start), duration),
+                None => (part.to_string(), None, -1),
+            }),
+        }
+        continued = more;
+    }
+
+    commands.retain(|(command, _, _)| !command.trim().is_empty());
+    commands
+}
+
+/// Read fish history, a list of entries like
+///
+/// ```text
+/// - cmd: vim src/main.rs
+///   when: 1700000000
+///   paths:
+///     - src/main.rs
+/// ```
+///
+/// The paths, which fish uses to only suggest commands whose files still
+/// exist, are kept as `path` metadata. Fish doesn't keep the cwd, duration
+/// or exit status.
+pub fn import_fish(path: impl AsRef<Path>) -> Result<Vec<HistoryItem>> {
+    let path = path.as_ref();
+    let text = String::from_utf8_lossy(&std::fs::read(path)?).into_owned();
+    let entries = parse_fish(&text);
+    let times = entries.iter().map(|e| e.1).collect::<Vec<_>>();
+    Ok(entries
+        .into_iter()
+        .zip(fill_timestamps(&times))
+        .map(|((line, _, paths), time)| {
+            let mut item = imported(line, String::new(), -1, UNKNOWN_EXIT_STATUS, 0, time);
+            if !paths.is_empty() {
+                item.metadata.insert("path".to_string(), paths);
+            }
+            item
+        })
+        .collect())
+}
+
+/// Undo fish's escaping of backslashes and newlines in its history file
+fn unescape_fish(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('\\')) => {
+                chars.next();
+                plain.push('\\');
+            }
+            ('\\', Some('n')) => {
+                chars.next();
+                plain.push('\n');
+            }
+            (c, _) => plain.push(c),
+        }
+    }
+    plain
+}
+
+/// The commands of fish history, with their time and paths
+fn parse_fish(text: &str) -> Vec<(String, Option<DateTime<Utc>>, Vec<String>)> {
+    let mut entries: Vec<(String, Option<DateTime<Utc>>, Vec<String>)> = vec![];
+    let mut in_paths = false;
+    for line in text.lines() {
+        if let Some(command) = line.strip_prefix("- cmd:") {
+            entries.push((unescape_fish(command.trim_start()), None, vec![]));
+            in_paths = false;
+            continue;
+        }
+        let (_, time, paths) = match entries.last_mut() {
+            Some(entry) => entry,
+            None => continue,
+        };
+        let trimmed = line.trim_start();
+        if let Some(when) = trimmed.strip_prefix("when:") {
+            *time = when
+                .trim()
+                .parse()
+                .ok()
+                .and_then(|secs| Utc.timestamp_opt(secs, 0).single());
+            in_paths = false;
+        } else if trimmed == "paths:" {
+            in_paths = true;
+        } else if let (true, Some(path)) = (in_paths, trimmed.strip_prefix("- ")) {
+            paths.push(unescape_fish(path));
+        } else {
+            in_paths = false;
+        }
+    }
+
+    entries.retain(|(command, _, _)| !command.trim().is_empty());
+    entries
+}
+
+/// A `session_id` for a session some other tool names with text, e.g. a
+/// UUID. The same name always gets the same number; the name itself is
+/// kept as `session` metadata.
+pub fn session_number(session: &str) -> i64 {
+    // FNV-1a, which unlike std's hashers is the same in every build
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in session.bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    (hash >> 1) as i64
+}
+
+/// Whether `table` of another tool's database has `column`, which older
+/// versions of the tool may not have added yet
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("select name from pragma_table_info('{}')", table))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        if row.get_ref(0)?.as_str()? == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Read Atuin's `history` table. It keeps times and durations in
+/// nanoseconds, the session as a UUID and the hostname as `host:user`,
+/// which is kept as `hostname` metadata along with Atuin's id. Commands
+/// deleted in Atuin are left out.
+pub fn import_atuin(path: impl AsRef<Path>) -> Result<Vec<HistoryItem>> {
+    let conn = open_read_only(path.as_ref())?;
+    let deleted = match has_column(&conn, "history", "deleted_at")? {
+        true => "where deleted_at is null",
+        false => "",
+    };
+    let mut stmt = conn.prepare(&format!(
+        "select id, timestamp, duration, exit, command, cwd, session, hostname
+        from history {} order by timestamp",
+        deleted
+    ))?;
+    let mut rows = stmt.query([])?;
+
+    let mut items: Vec<HistoryItem> = vec![];
+    while let Some(row) = rows.next()? {
+        let session: String = row.get("session")?;
+        let duration: i64 = row.get("duration")?;
+        let mut item = imported(
+            row.get("command")?,
+            row.get("cwd")?,
+            match duration {
+                d if d < 0 => -1,
+                d => d / 1_000_000,
+            },
+            row.get("exit")?,
+            session_number(&session),
+            Utc.timestamp_nanos(row.get("timestamp")?),
+        );
+        item.metadata.insert("session".to_string(), vec![session]);
+        item.metadata
+            .insert("hostname".to_string(), vec![row.get("hostname")?]);
+        item.metadata
+            .insert("atuin_id".to_string(), vec![row.get("id")?]);
+        items.push(item);
+    }
+    Ok(items)
+}
+
+/// Read McFly's `commands` table. It keeps start times in seconds, no
+/// duration or hostname, and the session as text. Where McFly recorded the
+/// directory a command changed from, it's kept as `old_dir` metadata, and
+/// commands picked from McFly's own search get `selected` metadata. The
+/// `cmd_tpl` column is left out, McFly derives it from the command.
+pub fn import_mcfly(path: impl AsRef<Path>) -> Result<Vec<HistoryItem>> {
+    let conn = open_read_only(path.as_ref())?;
+    let mut stmt = conn.prepare(
+        "select cmd, session_id, when_run, exit_code, selected, dir, old_dir
+        from commands order by id",
+    )?;
+    let mut rows = stmt.query([])?;
+
+    let mut items: Vec<HistoryItem> = vec![];
+    let mut times = vec![];
+    while let Some(row) = rows.next()? {
+        let session: String = row.get("session_id")?;
+        times.push(Utc.timestamp_opt(row.get("when_run")?, 0).single());
+        let mut item = imported(
+            row.get("cmd")?,
+            row.get::<_, Option<String>>("dir")?.unwrap_or_default(),
+            -1,
+            row.get("exit_code")?,
+            session_number(&session),
+            Utc::now(),
+        );
+        item.metadata.insert("session".to_string(), vec![session]);
+        if let Some(old_dir) = row.get::<_, Option<String>>("old_dir")? {
+            item.metadata.insert("old_dir".to_string(), vec![old_dir]);
+        }
+        if row.get::<_, bool>("selected")? {
+            item.metadata
+                .insert("selected".to_string(), vec!["true".to_string()]);
+        }
+        items.push(item);
+    }
+    for (item, time) in items.iter_mut().zip(fill_timestamps(&times)) {
+        item.timestamp = time;
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("rsq-import-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_split_command_line() {
+        assert_eq!(
+            split_command_line("  git commit -m wip"),
+            ("git".to_string(), Some("commit -m wip".to_string()))
+        );
+        assert_eq!(split_command_line("ls "), ("ls".to_string(), None));
+        assert_eq!(split_command_line(""), ("".to_string(), None));
+    }
+
+    #[test]
+    fn test_import_reedline() {
+        let path = temp_path("reedline");
+        let conn = Connection::open(&path).unwrap();
+        // the schema reedline creates
+        conn.execute_batch(
+            "create table history (
+                id integer primary key autoincrement,
+                command_line text not null,
+                start_timestamp integer,
+                session_id integer,
+                hostname text,
+                cwd text,
+                duration_ms integer,
+                exit_status integer,
+                more_info text
+            ) strict;
+            insert into history (command_line, start_timestamp, session_id, hostname, cwd, duration_ms, exit_status, more_info)
+            values ('cargo build --release', 1700000000123, 42, 'laptop', '/src/rsq', 5300, 101, '{\"shell\":\"nu\"}');
+            insert into history (command_line) values ('ls');",
+        )
+        .unwrap();
+        drop(conn);
+
+        let items = import_reedline(&path).unwrap();
+        assert_eq!(items.len(), 2);
+        let build = &items[0];
+        assert_eq!(build.command_line, "cargo build --release");
+        assert_eq!(build.command, "cargo");
+        assert_eq!(build.command_params.as_deref(), Some("build --release"));
+        assert_eq!(build.cwd, "/src/rsq");
+        assert_eq!(build.duration, 5300);
+        assert_eq!(build.exit_status, 101);
+        assert_eq!(build.session_id, 42);
+        assert_eq!(build.timestamp.timestamp_millis(), 1700000000123);
+        assert_eq!(build.metadata["hostname"], vec!["laptop"]);
+        assert_eq!(build.metadata["more_info"], vec![r#"{"shell":"nu"}"#]);
+
+        let ls = &items[1];
+        assert_eq!(ls.cwd, "");
+        assert_eq!(ls.duration, -1);
+        assert_eq!(ls.exit_status, UNKNOWN_EXIT_STATUS);
+        assert_eq!(ls.timestamp.timestamp_millis(), 1700000000124);
+        assert!(ls.metadata.is_empty());
+
+        assert!(import_reedline(temp_path("missing")).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_fill_timestamps() {
+        let at = |secs: i64| Utc.timestamp_opt(secs, 0).unwrap();
+        let ms = chrono::Duration::milliseconds;
+        assert_eq!(
+            fill_timestamps(&[None, Some(at(100)), None, Some(at(100)), Some(at(50))]),
+            vec![
+                at(100) - ms(1),
+                at(100),
+                at(100) + ms(1),
+                at(100) + ms(2),
+                at(50)
+            ]
+        );
+        assert_eq!(fill_timestamps(&[None, None]), vec![at(0), at(1)]);
+    }
+
+    #[test]
+    fn test_import_bash() {
+        let at = |secs: i64| Utc.timestamp_opt(secs, 0).unwrap();
+        let timed =
+            "#1700000000\ngit status\n#1700000060\nfor f in *; do\n  echo $f\ndone\n#1700000120\n";
+        assert_eq!(
+            parse_bash(timed),
+            vec![
+                ("git status".to_string(), Some(at(1700000000))),
+                (
+                    "for f in *; do\n  echo $f\ndone".to_string(),
+                    Some(at(1700000060))
+                ),
+            ]
+        );
+
+        let untimed = "ls -la\necho one \\\n  two\necho 'a\\\\'\n#not a timestamp\n";
+        let commands = parse_bash(untimed)
+            .into_iter()
+            .map(|(command, time)| {
+                assert_eq!(time, None);
+                command
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            commands,
+            vec![
+                "ls -la",
+                "echo one \\\n  two",
+                "echo 'a\\\\'",
+                "#not a timestamp"
+            ]
+        );
+
+        // HISTTIMEFORMAT unset after the last timestamp
+        let mixed = "echo early\n#1700000000\ngit status\nls\necho a \\\n  b\n";
+        assert_eq!(
+            parse_bash(mixed),
+            vec![
+                ("echo early".to_string(), None),
+                ("git status".to_string(), Some(at(1700000000))),
+                ("ls".to_string(), None),
+                ("echo a \\\n  b".to_string(), None),
+            ]
+        );
+
+        // the last command saved with lithist
+        let lithist = "#1700000000\ngit status\n#1700000060\nfor f in *; do\n  echo $f\ndone\n";
+        assert_eq!(parse_bash(lithist), parse_bash(timed));
+
+        let path = temp_path("bash");
+        std::fs::write(&path, timed).unwrap();
+        let items = import_bash(&path).unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1].command, "for");
+        assert_eq!(items[1].timestamp, at(1700000060));
+        assert_eq!(items[1].exit_status, UNKNOWN_EXIT_STATUS);
+
+        // commands without a time keep theirs when more are added
+        std::fs::write(&path, "ls\npwd\n").unwrap();
+        let before = import_bash(&path).unwrap();
+        std::fs::write(&path, "ls\npwd\ncd /tmp\n").unwrap();
+        let after = import_bash(&path).unwrap();
+        assert_eq!(before[..], after[..2]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_import_zsh() {
+        let at = |secs: i64| Utc.timestamp_opt(secs, 0).unwrap();
+        // `—` is 0xe2 0x80 0x94, and zsh metafies the 0x94
+        let mut history = b": 1700000000:12;cargo build\n".to_vec();
+        history.extend_from_slice(b": 1700000030:0;for f in *; do\\\n  echo $f\\\ndone\n");
+        history.extend_from_slice(b": 1700000040:1;echo \xe2\x80");
+        history.extend_from_slice(&[ZSH_META, 0x94 ^ 0x20]);
+        history.extend_from_slice(b"\nls\n");
+
+        let commands = parse_zsh(&String::from_utf8_lossy(&unmetafy(&history)));
+        assert_eq!(
+            commands,
+            vec![
+                ("cargo build".to_string(), Some(at(1700000000)), 12000),
+                (
+                    "for f in *; do\n  echo $f\ndone".to_string(),
+                    Some(at(1700000030)),
+                    0
+                ),
+                ("echo \u{2014}".to_string(), Some(at(1700000040)), 1000),
+                ("ls".to_string(), None, -1),
+            ]
+        );
+
+        let path = temp_path("zsh");
+        std::fs::write(&path, &history).unwrap();
+        let items = import_zsh(&path).unwrap();
+        assert_eq!(items.len(), 4);
+        assert_eq!(items[0].command, "cargo");
+        assert_eq!(items[0].duration, 12000);
+        assert_eq!(items[0].timestamp, at(1700000000));
+        assert_eq!(
+            items[3].timestamp,
+            at(1700000040) + chrono::Duration::milliseconds(1)
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_import_fish() {
+        let at = |secs: i64| Utc.timestamp_opt(secs, 0).unwrap();
+        let history = "- cmd: vim src/main.rs
+  when: 1700000000
+  paths:
+    - src/main.rs
+    - C:\\\\temp
+- cmd: echo 'one\\ntwo' \\\\n
+  when: 1700000060
+- cmd: ls
+";
+        assert_eq!(
+            parse_fish(history),
+            vec![
+                (
+                    "vim src/main.rs".to_string(),
+                    Some(at(1700000000)),
+                    vec!["src/main.rs".to_string(), "C:\\temp".to_string()]
+                ),
+                (
+                    "echo 'one\ntwo' \\n".to_string(),
+                    Some(at(1700000060)),
+                    vec![]
+                ),
+                ("ls".to_string(), None, vec![]),
+            ]
+        );
+
+        let path = temp_path("fish");
+        std::fs::write(&path, history).unwrap();
+        let items = import_fish(&path).unwrap();
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].metadata["path"], vec!["src/main.rs", "C:\\temp"]);
+        assert!(items[1].metadata.is_empty());
+        assert_eq!(
+            items[2].timestamp,
+            at(1700000060) + chrono::Duration::milliseconds(1)
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_import_atuin() {
+        let path = temp_path("atuin");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "create table history (
+                id text primary key,
+                timestamp integer not null,
+                duration integer not null,
+                exit integer not null,
+                command text not null,
+                cwd text not null,
+                session text not null,
+                hostname text not null,
+                deleted_at integer,
+                unique(timestamp, cwd, command)
+            );
+            insert into history values ('018bb6c0', 1700000000123456789, 2500000000, 1,
+                'cargo test --workspace', '/src/rsq', '018bb6c0a1b2', 'laptop:ellie', null);
+            insert into history values ('018bb6c1', 1700000001000000000, -1, 0,
+                'echo hunter2', '/src/rsq', '018bb6c0a1b2', 'laptop:ellie', 1700000002000000000);",
+        )
+        .unwrap();
+        drop(conn);
+
+        let items = import_atuin(&path).unwrap();
+        assert_eq!(items.len(), 1);
+        let test = &items[0];
+        assert_eq!(test.command_line, "cargo test --workspace");
+        assert_eq!(test.command, "cargo");
+        assert_eq!(test.cwd, "/src/rsq");
+        assert_eq!(test.duration, 2500);
+        assert_eq!(test.exit_status, 1);
+        assert_eq!(test.session_id, session_number("018bb6c0a1b2"));
+        assert_eq!(test.timestamp.timestamp_nanos(), 1700000000123456789);
+        assert_eq!(test.metadata["hostname"], vec!["laptop:ellie"]);
+        assert_eq!(test.metadata["session"], vec!["018bb6c0a1b2"]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_import_mcfly() {
+        let path = temp_path("mcfly");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "create table commands (
+                id integer primary key autoincrement,
+                cmd text not null,
+                cmd_tpl text,
+                session_id text not null,
+                when_run integer not null,
+                exit_code integer not null,
+                selected integer not null,
+                dir text,
+                old_dir text
+            );
+            insert into commands (cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir, old_dir)
+            values ('cd src', 'cd src', 'abc', 1700000000, 0, 1, '/home/ellie/src', '/home/ellie');
+            insert into commands (cmd, cmd_tpl, session_id, when_run, exit_code, selected, dir, old_dir)
+            values ('make', 'make', 'abc', 1700000000, 2, 0, '/home/ellie/src', null);",
+        )
+        .unwrap();
+        drop(conn);
+
+        let items = import_mcfly(&path).unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].cwd, "/home/ellie/src");
+        assert_eq!(items[0].metadata["old_dir"], vec!["/home/ellie"]);
+        assert_eq!(items[0].metadata["selected"], vec!["true"]);
+        assert_eq!(items[1].exit_status, 2);
+        assert_eq!(items[1].duration, -1);
+        assert_eq!(items[1].session_id, items[0].session_id);
+        assert!(!items[1].metadata.contains_key("old_dir"));
+        // run in the same second, but both kept
+        assert_eq!(
+            items[1].timestamp - items[0].timestamp,
+            chrono::Duration::milliseconds(1)
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+}
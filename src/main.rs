@@ -2,11 +2,20 @@
 #![allow(unused_variables)]
 
 pub mod database;
+pub mod fuzzy;
 pub mod history_item;
+pub mod import;
+pub mod query;
+pub mod typo;
 
-use crate::history_item::HistoryItem;
-use chrono::{DateTime, NaiveDate};
-use database::{Database, SearchMode, Sqlite};
+use crate::history_item::{HistoryItem, UniqueKey};
+use crate::import::ImportFormat;
+use chrono::{DateTime, NaiveDate, Utc};
+use database::{
+    CaseMode, ContextWeights, Database, ExitFilter, FrecencyWeights, HistoryAction, HistoryFilter,
+    HistoryUpdate, OpenMode, Ranking, RankingKind, SearchContext, SearchField, SearchMode,
+    SearchOptions, Sqlite,
+};
 use lazy_static::lazy_static;
 use log::debug;
 // use rusqlite::{config::DbConfig, params, Connection, Result as SqliteError};
@@ -14,8 +23,9 @@ use anyhow::Result;
 use simplelog::*;
 use std::convert::TryInto;
 use std::io::BufRead;
-use std::io::{self, BufReader, Read};
+use std::io::{self, BufReader, IsTerminal, Read, Write};
 use std::io::{Seek, SeekFrom};
+use std::ops::Range;
 use std::{fs::File, path::PathBuf};
 use structopt::StructOpt;
 
@@ -25,10 +35,158 @@ lazy_static! {
 
 #[derive(StructOpt)]
 struct Args {
+    /// readwrite, readonly, snapshot or immutable
+    #[structopt(short = "o", long = "open_mode", default_value = "readwrite")]
+    open_mode: OpenMode,
+    /// Another history database to include in search, select, range, count,
+    /// first, last, before and suggest, named after its file name
+    #[structopt(short = "a", long = "attach", number_of_values = 1)]
+    attach: Vec<PathBuf>,
     #[structopt(subcommand)]
     cmd: Option<HizteryCmd>,
 }
 
+#[derive(StructOpt)]
+struct FilterArgs {
+    /// Text contained in the command line or command
+    #[structopt(long = "contains")]
+    contains: Option<String>,
+    #[structopt(long = "command")]
+    command: Option<String>,
+    #[structopt(long = "cwd")]
+    cwd: Option<String>,
+    /// Working directory is this directory or under it, taken as written
+    #[structopt(long = "cwd_prefix")]
+    cwd_prefix: Option<String>,
+    #[structopt(flatten)]
+    scope: ScopeArgs,
+    #[structopt(long = "session")]
+    session_id: Option<i64>,
+    /// any, success, failure or exit codes, e.g. 1,127
+    #[structopt(long = "exit_status", default_value = "any")]
+    exit_status: ExitFilter,
+    #[structopt(short = "f", long = "from")]
+    from_date: Option<String>,
+    #[structopt(short = "t", long = "to")]
+    to_date: Option<String>,
+}
+
+impl FilterArgs {
+    fn to_filter(&self) -> Result<HistoryFilter> {
+        let parse_date = |date: &Option<String>| -> Result<Option<DateTime<Utc>>> {
+            Ok(match date {
+                Some(d) => {
+                    let d = NaiveDate::parse_from_str(d, "%Y-%m-%d")?;
+                    Some(DateTime::<Utc>::from_utc(
+                        d.and_hms_opt(0, 0, 0).unwrap(),
+                        Utc,
+                    ))
+                }
+                None => None,
+            })
+        };
+
+        Ok(HistoryFilter {
+            contains: self.contains.clone(),
+            command: self.command.clone(),
+            cwd: self.cwd.clone(),
+            cwd_prefix: self.cwd_prefix.clone(),
+            cwd_under: self.scope.to_dir(),
+            session_id: self.session_id,
+            exit_status: self.exit_status.clone(),
+            from: parse_date(&self.from_date)?,
+            to: parse_date(&self.to_date)?,
+        })
+    }
+}
+
+#[derive(StructOpt)]
+struct ScopeArgs {
+    /// Only commands run in the current directory or under it
+    #[structopt(long = "here")]
+    here: bool,
+    /// Only commands run in this directory or under it. A relative path is
+    /// resolved from the current directory.
+    #[structopt(long = "under", conflicts_with = "here")]
+    under: Option<String>,
+}
+
+impl ScopeArgs {
+    fn to_dir(&self) -> Option<String> {
+        match (&self.under, self.here) {
+            (Some(dir), _) => Some(dir.clone()),
+            (None, true) => Some(".".to_string()),
+            (None, false) => None,
+        }
+    }
+}
+
+#[derive(StructOpt)]
+struct RankArgs {
+    /// recency, frecency or context
+    #[structopt(short = "k", long = "rank", default_value = "recency")]
+    rank: RankingKind,
+    #[structopt(flatten)]
+    weights: WeightArgs,
+}
+
+impl RankArgs {
+    fn to_ranking(&self) -> Ranking {
+        match self.rank {
+            RankingKind::Recency => Ranking::Recency,
+            RankingKind::Frecency => Ranking::Frecency(self.weights.to_frecency()),
+            RankingKind::Context => Ranking::Context(self.weights.to_context()),
+        }
+    }
+}
+
+#[derive(StructOpt)]
+struct WeightArgs {
+    /// Frecency weight of the summed run_count
+    #[structopt(long = "run_weight")]
+    run_weight: Option<f64>,
+    /// Frecency weight of the number of history rows
+    #[structopt(long = "exec_weight")]
+    exec_weight: Option<f64>,
+    /// Frecency weight every command gets
+    #[structopt(long = "recency_weight")]
+    recency_weight: Option<f64>,
+    /// Hours after which a frecency score has halved
+    #[structopt(long = "half_life")]
+    half_life_hours: Option<f64>,
+    /// Context boost for every run in the context's cwd
+    #[structopt(long = "cwd_weight")]
+    cwd_weight: Option<f64>,
+    /// Context boost for every run in the context's session
+    #[structopt(long = "session_weight")]
+    session_weight: Option<f64>,
+    /// Context boost for every run right after the context's last command
+    #[structopt(long = "follows_weight")]
+    follows_weight: Option<f64>,
+}
+
+impl WeightArgs {
+    fn to_frecency(&self) -> FrecencyWeights {
+        let default = FrecencyWeights::default();
+        FrecencyWeights {
+            run_count: self.run_weight.unwrap_or(default.run_count),
+            executions: self.exec_weight.unwrap_or(default.executions),
+            recency: self.recency_weight.unwrap_or(default.recency),
+            half_life_hours: self.half_life_hours.unwrap_or(default.half_life_hours),
+        }
+    }
+
+    fn to_context(&self) -> ContextWeights {
+        let default = ContextWeights::default();
+        ContextWeights {
+            frecency: self.to_frecency(),
+            cwd: self.cwd_weight.unwrap_or(default.cwd),
+            session: self.session_weight.unwrap_or(default.session),
+            follows: self.follows_weight.unwrap_or(default.follows),
+        }
+    }
+}
+
 #[derive(StructOpt)]
 #[structopt(about = "sql commands used with history")]
 enum HizteryCmd {
@@ -53,10 +211,23 @@ enum HizteryCmd {
         max: Option<usize>,
         #[structopt(short = "u", long = "unique")]
         unique: bool,
+        /// What makes rows the same with --unique: command, command_line or command_line_cwd
+        #[structopt(long = "unique_by", default_value = "command")]
+        unique_by: UniqueKey,
+        #[structopt(flatten)]
+        rank: RankArgs,
+        /// any, success, failure or exit codes, e.g. 1,127
+        #[structopt(short = "x", long = "exit_status", default_value = "any")]
+        exit_status: ExitFilter,
+        #[structopt(flatten)]
+        scope: ScopeArgs,
     },
     Import {
         #[structopt(short = "f", long = "file", name = "file path")]
         nushell_history_filepath: String,
+        /// nushell (plaintext), reedline (sqlite), bash, zsh, fish, atuin or mcfly
+        #[structopt(long = "format", default_value = "nushell")]
+        format: ImportFormat,
     },
     Search {
         #[structopt(short = "m", long = "mode")]
@@ -65,6 +236,31 @@ enum HizteryCmd {
         limit: Option<i64>,
         #[structopt(short = "q", long = "query")]
         query: String,
+        #[structopt(flatten)]
+        rank: RankArgs,
+        /// Directory the context ranking favours, the current one by default
+        #[structopt(long = "ctx_cwd")]
+        ctx_cwd: Option<String>,
+        /// Session the context ranking favours
+        #[structopt(long = "ctx_session")]
+        ctx_session: Option<i64>,
+        /// The context ranking favours commands usually run after this command line
+        #[structopt(long = "ctx_last")]
+        ctx_last: Option<String>,
+        /// command, command_line, params, cwd or any
+        #[structopt(short = "F", long = "field", default_value = "command")]
+        field: SearchField,
+        /// smart (case sensitive only when the query has upper case), sensitive or insensitive
+        #[structopt(short = "c", long = "case", default_value = "smart")]
+        case: CaseMode,
+        /// any, success, failure or exit codes, e.g. 1,127
+        #[structopt(short = "x", long = "exit_status", default_value = "any")]
+        exit_status: ExitFilter,
+        #[structopt(flatten)]
+        scope: ScopeArgs,
+        /// What makes hits the same: command, command_line or command_line_cwd
+        #[structopt(long = "unique_by", default_value = "command")]
+        unique_by: UniqueKey,
     },
     Count {},
     Last {},
@@ -86,6 +282,67 @@ enum HizteryCmd {
         count: i64,
     },
     All {},
+    UpdateWhere {
+        #[structopt(flatten)]
+        filter: FilterArgs,
+        #[structopt(long = "set_command_line", name = "set_command_line")]
+        command_line: Option<String>,
+        #[structopt(long = "set_command", name = "set_command")]
+        command: Option<String>,
+        #[structopt(long = "set_params", name = "set_params")]
+        command_params: Option<String>,
+        #[structopt(long = "set_cwd", name = "set_cwd")]
+        cwd: Option<String>,
+        /// Move working directories at or under OLD to the same place under NEW
+        #[structopt(long = "move_cwd", number_of_values = 2, value_names = &["OLD", "NEW"])]
+        move_cwd: Vec<String>,
+        #[structopt(short = "n", long = "dry_run")]
+        dry_run: bool,
+        /// Don't ask for confirmation
+        #[structopt(short = "y", long = "yes")]
+        yes: bool,
+    },
+    DeleteWhere {
+        #[structopt(flatten)]
+        filter: FilterArgs,
+        #[structopt(short = "n", long = "dry_run")]
+        dry_run: bool,
+        /// Don't ask for confirmation
+        #[structopt(short = "y", long = "yes")]
+        yes: bool,
+    },
+    Tail {
+        #[structopt(short = "n", long = "lines", default_value = "10")]
+        lines: usize,
+        #[structopt(short = "i", long = "interval", default_value = "500")]
+        interval_ms: u64,
+    },
+    /// The most likely command line starting with the prefix
+    Suggest {
+        #[structopt(short = "q", long = "query")]
+        prefix: String,
+        /// The current directory by default
+        #[structopt(long = "ctx_cwd")]
+        ctx_cwd: Option<String>,
+        #[structopt(long = "ctx_session")]
+        ctx_session: Option<i64>,
+        #[structopt(flatten)]
+        weights: WeightArgs,
+    },
+    /// The command lines most likely to follow a command line, the last one run by default
+    Next {
+        #[structopt(short = "q", long = "query")]
+        last_command: Option<String>,
+        #[structopt(short = "n", long = "lines", default_value = "5")]
+        lines: usize,
+    },
+    /// Recent commands that failed, or exited with `--exit_status`
+    Failures {
+        #[structopt(short = "n", long = "lines", default_value = "20")]
+        lines: usize,
+        #[structopt(flatten)]
+        filter: FilterArgs,
+    },
 }
 
 #[paw::main]
@@ -116,10 +373,18 @@ fn start(args: Args) -> Result<(), anyhow::Error> {
 
     let db_path = PathBuf::from("my_hizzy.db");
     // PathBuf::from("C:\\Users\\dschroeder\\source\\repos\\forks\\sql\\hiztery\\hizzy.db");
-    let mut sqlite = match Sqlite::new(db_path, database::SqlLogMode::Trace) {
-        Ok(r) => r,
-        Err(e) => return Err(e),
-    };
+    let mut sqlite = Sqlite::open(db_path, database::SqlLogMode::Trace, args.open_mode)?;
+    for path in args.attach.iter() {
+        // my_hizzy-2020.db is attached as my_hizzy_2020
+        let name: String = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        sqlite.attach(path, &name)?;
+    }
 
     match args.cmd {
         Some(HizteryCmd::Insert {
@@ -142,7 +407,7 @@ fn start(args: Args) -> Result<(), anyhow::Error> {
                     1,
                 );
 
-                let result = sqlite.save(&hi)?;
+                sqlite.save(&hi)?;
             }
         }
         Some(HizteryCmd::Update {
@@ -172,50 +437,118 @@ fn start(args: Args) -> Result<(), anyhow::Error> {
             let res = sqlite.delete_history_item(history_id)?;
             debug!("Deleted row count: [{}]", res);
         }
-        Some(HizteryCmd::Select { max, unique }) => {
+        Some(HizteryCmd::Select {
+            max,
+            unique,
+            unique_by,
+            rank,
+            exit_status,
+            scope,
+        }) => {
             // cargo run -- select -m 5 -u
             debug!("Selecting max: [{:?}] with unique: [{}]", max, unique);
-            let output = sqlite.list(max, unique)?;
+            let ranking = rank.to_ranking();
+            let cwd_under = scope.to_dir();
+            let filtered = exit_status != ExitFilter::Any || cwd_under.is_some();
+            if ranking != Ranking::Recency || (unique && filtered) {
+                // cargo run -- select -m 5 -k frecency
+                // cargo run -- select -m 5 -u -x failure
+                // cargo run -- select -m 5 -u --here
+                let options = SearchOptions {
+                    limit: max.map(|m| m as i64),
+                    exit_status,
+                    cwd_under,
+                    unique_by,
+                    ranking,
+                    ..Default::default()
+                };
+                let output = sqlite.search_ranked("", &options)?;
+                for (idx, hit) in output.iter().enumerate() {
+                    debug!(
+                        "ItemNum: [{}] Score: [{:.3}] Row: [{:?}]",
+                        idx, hit.score, hit.item
+                    );
+                }
+                return Ok(());
+            }
+            let output = if filtered {
+                // cargo run -- select -m 5 -x 1,127
+                // cargo run -- select -m 5 --under ~/src/rsq
+                let filter = HistoryFilter {
+                    exit_status,
+                    cwd_under,
+                    ..Default::default()
+                };
+                let mut output = sqlite.select_where(&filter)?;
+                output.truncate(max.unwrap_or(usize::MAX));
+                output
+            } else if unique {
+                // cargo run -- select -m 5 -u --unique_by command_line_cwd
+                sqlite.list_unique(max, unique_by)?
+            } else {
+                sqlite.list(max, false)?
+            };
             for (idx, item) in output.iter().enumerate() {
                 debug!("ItemNum: [{}] Row: [{:?}]", idx, item);
             }
         }
         Some(HizteryCmd::Import {
             nushell_history_filepath,
+            format,
         }) => {
             // cargo run -- --file c:\path\to\nushell\history.txt
-            debug!("Import with file: {}", &nushell_history_filepath);
-            let file = File::open(nushell_history_filepath);
-            let mut reader = BufReader::new(file.unwrap());
-            let lines = count_lines(&mut reader)?;
-            debug!("Lines: {}", lines);
+            debug!(
+                "Import with file: {} as {}",
+                &nushell_history_filepath, format
+            );
+            let history_vec = match format {
+                ImportFormat::Nushell => {
+                    let file = File::open(nushell_history_filepath);
+                    let mut reader = BufReader::new(file.unwrap());
+                    let lines = count_lines(&mut reader)?;
+                    debug!("Lines: {}", lines);
 
-            let mut history_vec = vec![];
+                    let mut history_vec = vec![];
 
-            for (idx, line) in reader.lines().enumerate() {
-                // println!("{}", line?);
-                let time = chrono::Utc::now();
-                let offset = chrono::Duration::seconds(idx.try_into().unwrap());
-                let time = time - offset;
+                    for (idx, line) in reader.lines().enumerate() {
+                        // println!("{}", line?);
+                        let time = chrono::Utc::now();
+                        let offset = chrono::Duration::seconds(idx.try_into().unwrap());
+                        let time = time - offset;
 
-                // self.counter += 1;
+                        // self.counter += 1;
 
-                history_vec.push(HistoryItem::new(
-                    None,
-                    "cmd_line".to_string(),
-                    line?.trim_end().to_string(),
-                    Some(String::from("unknown")),
-                    "some/path".to_string(),
-                    -1,
-                    0,
-                    Some(*PID),
-                    time,
-                    1,
-                ));
-            }
+                        history_vec.push(HistoryItem::new(
+                            None,
+                            "cmd_line".to_string(),
+                            line?.trim_end().to_string(),
+                            Some(String::from("unknown")),
+                            "some/path".to_string(),
+                            -1,
+                            0,
+                            Some(*PID),
+                            time,
+                            1,
+                        ));
+                    }
+                    history_vec
+                }
+                // cargo run -- import --format reedline --file ~/.config/nushell/history.sqlite3
+                ImportFormat::Reedline => import::import_reedline(&nushell_history_filepath)?,
+                // cargo run -- import --format bash --file ~/.bash_history
+                ImportFormat::Bash => import::import_bash(&nushell_history_filepath)?,
+                // cargo run -- import --format zsh --file ~/.zsh_history
+                ImportFormat::Zsh => import::import_zsh(&nushell_history_filepath)?,
+                // cargo run -- import --format fish --file ~/.local/share/fish/fish_history
+                ImportFormat::Fish => import::import_fish(&nushell_history_filepath)?,
+                // cargo run -- import --format atuin --file ~/.local/share/atuin/history.db
+                ImportFormat::Atuin => import::import_atuin(&nushell_history_filepath)?,
+                // cargo run -- import --format mcfly --file ~/.local/share/mcfly/history.db
+                ImportFormat::McFly => import::import_mcfly(&nushell_history_filepath)?,
+            };
 
             debug!("Preparing for save_bulk");
-            let result = sqlite.save_bulk(&history_vec)?;
+            sqlite.save_bulk(&history_vec)?;
             let cnt = sqlite.history_count()?;
             debug!("Imported [{}] history entries", cnt);
         }
@@ -223,6 +556,15 @@ fn start(args: Args) -> Result<(), anyhow::Error> {
             search_mode,
             limit,
             query,
+            rank,
+            ctx_cwd,
+            ctx_session,
+            ctx_last,
+            field,
+            case,
+            exit_status,
+            scope,
+            unique_by,
         }) => {
             // cargo run -- search -m "p" -q "code"
             debug!(
@@ -233,18 +575,57 @@ fn start(args: Args) -> Result<(), anyhow::Error> {
                 "p" => SearchMode::Prefix,
                 "f" => SearchMode::FullText,
                 "z" => SearchMode::Fuzzy,
+                "r" => SearchMode::Regex,
+                "b" => SearchMode::Boolean,
+                "t" => SearchMode::Typo,
                 _ => SearchMode::FullText,
             };
 
-            let result = sqlite.search(limit, s_mode, &query);
-            match result {
-                Ok(r) => {
-                    debug!("Found {} hits", r.len());
-                    for (idx, hit) in r.iter().enumerate() {
-                        debug!("Hit # [{}] History: [{}]", idx + 1, hit.command);
-                    }
+            // cargo run -- search -m "f" -q "cargo" -k frecency
+            // cargo run -- search -m "p" -q "~/src/infra" -F cwd
+            // cargo run -- search -m "f" -q "makefile" -c sensitive
+            // cargo run -- search -m "t" -q "kubctl get pods"
+            // cargo run -- search -m "p" -q "cargo" -k context --ctx_last "cargo fmt"
+            let cwd = match ctx_cwd {
+                Some(cwd) => Some(cwd),
+                None => std::env::current_dir()
+                    .ok()
+                    .map(|d| d.to_string_lossy().to_string()),
+            };
+            let ranking = rank.to_ranking();
+            let show_score = ranking != Ranking::Recency || s_mode.is_scored();
+            let options = SearchOptions {
+                limit,
+                mode: s_mode,
+                field,
+                case,
+                exit_status,
+                cwd_under: scope.to_dir(),
+                unique_by,
+                ranking,
+                context: Some(SearchContext {
+                    cwd,
+                    session_id: ctx_session,
+                    last_command_line: ctx_last,
+                }),
+            };
+            // a bad regex or query is an error, not an empty result
+            let result = sqlite.search_ranked(&query, &options)?;
+            debug!("Found {} hits", result.len());
+            if result.is_empty() {
+                debug!("No hits found for phrase: {}", &query);
+            }
+            let colour = io::stdout().is_terminal();
+            for (idx, hit) in result.iter().enumerate() {
+                let mut line = format!("{:>4}  ", idx + 1);
+                if show_score {
+                    line += &format!("{:>10.3}  ", hit.score);
                 }
-                _ => debug!("No hits found for phrase: {}", &query),
+                if let Some(source) = &hit.item.source {
+                    line += &format!("[{}] ", source);
+                }
+                line += &highlight(hit.text(), &hit.matches, colour);
+                println!("{}", line);
             }
         }
         Some(HizteryCmd::Count {}) => {
@@ -279,8 +660,10 @@ fn start(args: Args) -> Result<(), anyhow::Error> {
             );
             let f = NaiveDate::parse_from_str(&from_date, "%Y-%m-%d").unwrap();
             let t = NaiveDate::parse_from_str(&to_date, "%Y-%m-%d").unwrap();
-            let f_utc = DateTime::<chrono::Utc>::from_utc(f.and_hms(0, 0, 0), chrono::Utc);
-            let t_utc = DateTime::<chrono::Utc>::from_utc(t.and_hms(0, 0, 0), chrono::Utc);
+            let f_utc =
+                DateTime::<chrono::Utc>::from_utc(f.and_hms_opt(0, 0, 0).unwrap(), chrono::Utc);
+            let t_utc =
+                DateTime::<chrono::Utc>::from_utc(t.and_hms_opt(0, 0, 0).unwrap(), chrono::Utc);
             let result = sqlite.range(f_utc, t_utc)?;
 
             debug!("Found {} hits", result.len());
@@ -295,7 +678,8 @@ fn start(args: Args) -> Result<(), anyhow::Error> {
                 &from_date, count,
             );
             let f = NaiveDate::parse_from_str(&from_date, "%Y-%m-%d").unwrap();
-            let f_utc = DateTime::<chrono::Utc>::from_utc(f.and_hms(0, 0, 0), chrono::Utc);
+            let f_utc =
+                DateTime::<chrono::Utc>::from_utc(f.and_hms_opt(0, 0, 0).unwrap(), chrono::Utc);
             let result = sqlite.before(f_utc, count)?;
 
             debug!("Found {} hits", result.len());
@@ -312,12 +696,208 @@ fn start(args: Args) -> Result<(), anyhow::Error> {
                 debug!("Hit # [{}] History: [{:?}]", idx + 1, hit);
             }
         }
+        Some(HizteryCmd::UpdateWhere {
+            filter,
+            command_line,
+            command,
+            command_params,
+            cwd,
+            move_cwd,
+            dry_run,
+            yes,
+        }) => {
+            // cargo run -- update-where --cwd_prefix /old/checkout --move_cwd /old/checkout /new/checkout
+            let filter = filter.to_filter()?;
+            let changes = HistoryUpdate {
+                command_line,
+                command,
+                command_params,
+                cwd,
+                move_cwd: match move_cwd.as_slice() {
+                    [from, to] => Some((from.clone(), to.clone())),
+                    _ => None,
+                },
+            };
+            debug!("Updating where [{:?}] with [{:?}]", filter, changes);
+
+            let count = sqlite.update_where(&filter, &changes, true)?;
+            preview(&sqlite.select_update(&filter, &changes)?, count);
+            if !dry_run
+                && count > 0
+                && (yes || confirm(&format!("Update {} history items?", count))?)
+            {
+                let count = sqlite.update_where(&filter, &changes, false)?;
+                debug!("Updated row count: [{}]", count);
+            }
+        }
+        Some(HizteryCmd::DeleteWhere {
+            filter,
+            dry_run,
+            yes,
+        }) => {
+            // cargo run -- delete-where --contains "hunter2" --dry_run
+            let filter = filter.to_filter()?;
+            debug!("Deleting where [{:?}]", filter);
+
+            let count = sqlite.delete_where(&filter, true)?;
+            preview(&sqlite.select_where(&filter)?, count);
+            if !dry_run
+                && count > 0
+                && (yes || confirm(&format!("Delete {} history items?", count))?)
+            {
+                let count = sqlite.delete_where(&filter, false)?;
+                debug!("Deleted row count: [{}]", count);
+            }
+        }
+        Some(HizteryCmd::Tail { lines, interval_ms }) => {
+            // cargo run -- tail -n 5 -i 250
+            debug!(
+                "Following history with [{}] lines every [{}]ms.",
+                lines, interval_ms
+            );
+            let changes = sqlite.subscribe()?;
+            let mut result = sqlite.list(Some(lines), false)?;
+            result.reverse();
+            for hit in result.iter() {
+                debug!("History: [{:?}]", hit);
+            }
+
+            loop {
+                sqlite.poll_changes()?;
+                for event in changes.try_iter() {
+                    match (event.action, event.history_id) {
+                        (HistoryAction::Gap, _) | (_, None) => {
+                            debug!("Some changes were missed")
+                        }
+                        (HistoryAction::Deleted, Some(id)) => {
+                            debug!("Deleted history item [{}]", id)
+                        }
+                        (action, Some(id)) => match sqlite.load(&id.to_string()) {
+                            Ok(hit) => debug!("{:?}: [{:?}]", action, hit),
+                            // it may have been deleted again before we got to it
+                            Err(_) => debug!("{:?}: [{}]", action, id),
+                        },
+                    }
+                }
+                std::thread::sleep(std::time::Duration::from_millis(interval_ms));
+            }
+        }
+        Some(HizteryCmd::Suggest {
+            prefix,
+            ctx_cwd,
+            ctx_session,
+            weights,
+        }) => {
+            // cargo run -- suggest -q "git c" --cwd_weight 10
+            let context = SearchContext {
+                cwd: ctx_cwd.or_else(|| {
+                    std::env::current_dir()
+                        .ok()
+                        .map(|d| d.to_string_lossy().to_string())
+                }),
+                session_id: ctx_session,
+                ..Default::default()
+            };
+            let start = std::time::Instant::now();
+            let suggestion = sqlite.suggest(&prefix, &context, &weights.to_context())?;
+            debug!("Suggested [{:?}] in [{:?}]", suggestion, start.elapsed());
+            if let Some(suggestion) = suggestion {
+                println!("{}", suggestion);
+            }
+        }
+        Some(HizteryCmd::Next {
+            last_command,
+            lines,
+        }) => {
+            // cargo run -- next -q "cargo fmt"
+            let last_command = match last_command {
+                Some(command) => command,
+                None => sqlite.last()?.command_line,
+            };
+            debug!("Predicting what follows [{}]", last_command);
+            let mut result = sqlite.predict_next(&last_command)?;
+            result.truncate(lines);
+            for (idx, prediction) in result.iter().enumerate() {
+                println!(
+                    "{:>4}  {:>5.1}%  {}",
+                    idx + 1,
+                    prediction.probability * 100.0,
+                    prediction.command_line
+                );
+            }
+        }
+        Some(HizteryCmd::Failures { lines, filter }) => {
+            // cargo run -- failures -n 5 --cwd_prefix /home/ellie/src
+            let mut filter = filter.to_filter()?;
+            if filter.exit_status == ExitFilter::Any {
+                filter.exit_status = ExitFilter::Failure;
+            }
+            debug!("Listing [{}] failures where {:?}", lines, filter);
+            let mut result = sqlite.select_where(&filter)?;
+            result.truncate(lines);
+            for (idx, hit) in result.iter().enumerate() {
+                println!(
+                    "{:>4}  {:>3}  {:>8}  {}  ({})",
+                    idx + 1,
+                    hit.exit_status,
+                    format_duration(hit.duration),
+                    hit.command,
+                    hit.cwd
+                );
+            }
+        }
         None => {}
     }
 
     Ok(())
 }
 
+/// A duration in milliseconds, `?` when it wasn't recorded
+fn format_duration(ms: i64) -> String {
+    match ms {
+        ms if ms < 0 => "?".to_string(),
+        ms if ms < 1000 => format!("{}ms", ms),
+        ms if ms < 60_000 => format!("{:.1}s", ms as f64 / 1000.0),
+        ms => format!("{}m{:02}s", ms / 60_000, ms % 60_000 / 1000),
+    }
+}
+
+fn preview(result: &[HistoryItem], count: usize) {
+    for (idx, hit) in result.iter().enumerate() {
+        debug!("Match # [{}] History: [{:?}]", idx + 1, hit);
+    }
+    debug!("[{}] history items would change", count);
+}
+
+/// `text` with the byte ranges in `matches` in bold red when `colour` is set
+fn highlight(text: &str, matches: &[Range<usize>], colour: bool) -> String {
+    if !colour {
+        return text.to_string();
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut at = 0;
+    for m in matches {
+        // overlapping ranges would slice backwards
+        if m.start < at {
+            continue;
+        }
+        out += &text[at..m.start];
+        out += &format!("\x1b[1;31m{}\x1b[0m", &text[m.clone()]);
+        at = m.end;
+    }
+    out += &text[at..];
+    out
+}
+
+fn confirm(question: &str) -> Result<bool> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
 fn count_lines(buf: &mut BufReader<impl Read + Seek>) -> Result<usize, io::Error> {
     let lines = buf.lines().count();
     buf.seek(SeekFrom::Start(0))?;
@@ -0,0 +1,246 @@
+// Boolean search queries
+//
+// Words are separated by whitespace and all of them have to match, e.g.
+// `docker build`. `OR` (or `|`) between two words matches either of them,
+// `-word` or `NOT word` excludes results containing it, `"quoted text"` is
+// matched as one phrase and parentheses group, e.g.
+// `docker -compose "build --no-cache"` or `(git | tig) NOT rebase`. NOT
+// binds tighter than AND, which binds tighter than OR.
+//
+// A `-` only negates at the start of a word that doesn't start with `--`,
+// so `ls --all` still looks for `--all`. In words `*` matches anything,
+// phrases are taken literally.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+/// A parsed boolean search query
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Query {
+    Word(String),
+    Phrase(String),
+    Not(Box<Query>),
+    /// Matches everything when empty
+    And(Vec<Query>),
+    Or(Vec<Query>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Phrase(String),
+    Not,
+    Or,
+    Open,
+    Close,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | '|' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    _ => Token::Or,
+                });
+            }
+            '"' => {
+                chars.next();
+                let mut phrase = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => phrase.push(c),
+                        None => bail!("unterminated phrase in query {:?}", input),
+                    }
+                }
+                tokens.push(Token::Phrase(phrase));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "()|\"".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                match word.as_str() {
+                    "OR" => tokens.push(Token::Or),
+                    "NOT" => tokens.push(Token::Not),
+                    w if w.starts_with('-') && !w.starts_with("--") => {
+                        tokens.push(Token::Not);
+                        if w.len() > 1 {
+                            tokens.push(Token::Word(w[1..].to_string()));
+                        }
+                    }
+                    _ => tokens.push(Token::Word(word)),
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.at).cloned();
+        self.at += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Query> {
+        let mut terms = vec![self.and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            terms.push(self.and()?);
+        }
+        if terms.len() > 1 && terms.contains(&Query::And(vec![])) {
+            bail!("missing search term next to OR");
+        }
+        Ok(match terms.len() {
+            1 => terms.remove(0),
+            _ => Query::Or(terms),
+        })
+    }
+
+    fn and(&mut self) -> Result<Query> {
+        let mut terms = vec![];
+        while !matches!(self.peek(), None | Some(Token::Or) | Some(Token::Close)) {
+            terms.push(self.unary()?);
+        }
+        Ok(match terms.len() {
+            1 => terms.remove(0),
+            _ => Query::And(terms),
+        })
+    }
+
+    fn unary(&mut self) -> Result<Query> {
+        match self.next() {
+            Some(Token::Word(word)) => Ok(Query::Word(word)),
+            Some(Token::Phrase(phrase)) => Ok(Query::Phrase(phrase)),
+            Some(Token::Not) => match self.peek() {
+                None | Some(Token::Or) | Some(Token::Close) => {
+                    bail!("missing search term after NOT")
+                }
+                _ => Ok(Query::Not(Box::new(self.unary()?))),
+            },
+            Some(Token::Open) => {
+                let query = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(query),
+                    _ => bail!("missing closing parenthesis"),
+                }
+            }
+            token => bail!("unexpected {:?}", token),
+        }
+    }
+}
+
+impl Query {
+    pub fn parse(input: &str) -> Result<Query> {
+        let mut parser = Parser {
+            tokens: tokenize(input)?,
+            at: 0,
+        };
+        let query = parser.or()?;
+        if let Some(token) = parser.peek() {
+            bail!("unexpected {:?} in query {:?}", token, input);
+        }
+        Ok(query)
+    }
+
+    /// The text of every word and phrase
+    pub fn terms(&self) -> Vec<&str> {
+        match self {
+            Query::Word(text) | Query::Phrase(text) => vec![text.as_str()],
+            Query::Not(query) => query.terms(),
+            Query::And(queries) | Query::Or(queries) => {
+                queries.iter().flat_map(|q| q.terms()).collect()
+            }
+        }
+    }
+
+    /// The words and phrases that aren't excluded, i.e. the ones a result
+    /// can be highlighted for
+    pub fn positive(&self) -> Vec<&Query> {
+        match self {
+            Query::Word(_) | Query::Phrase(_) => vec![self],
+            Query::Not(_) => vec![],
+            Query::And(queries) | Query::Or(queries) => {
+                queries.iter().flat_map(|q| q.positive()).collect()
+            }
+        }
+    }
+}
+
+impl core::str::FromStr for Query {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Query::parse(s)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn word(text: &str) -> Query {
+        Query::Word(text.to_string())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Query::parse(r#"docker -compose "build --no-cache""#).unwrap(),
+            Query::And(vec![
+                word("docker"),
+                Query::Not(Box::new(word("compose"))),
+                Query::Phrase("build --no-cache".to_string()),
+            ])
+        );
+        assert_eq!(
+            Query::parse("(git | tig) NOT rebase").unwrap(),
+            Query::And(vec![
+                Query::Or(vec![word("git"), word("tig")]),
+                Query::Not(Box::new(word("rebase"))),
+            ])
+        );
+        assert_eq!(
+            Query::parse("a b OR c").unwrap(),
+            Query::Or(vec![Query::And(vec![word("a"), word("b")]), word("c")])
+        );
+        assert_eq!(
+            Query::parse("ls --all").unwrap().terms(),
+            vec!["ls", "--all"]
+        );
+        assert_eq!(Query::parse("  ").unwrap(), Query::And(vec![]));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Query::parse(r#"docker "build"#).is_err());
+        assert!(Query::parse("(git | tig").is_err());
+        assert!(Query::parse("git )").is_err());
+        assert!(Query::parse("git OR").is_err());
+        assert!(Query::parse("git -").is_err());
+    }
+}
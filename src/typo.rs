@@ -0,0 +1,169 @@
+// Typo tolerant matching
+//
+// A query matches when some part of the text is at most a few edits away
+// from it: a character inserted, deleted or replaced, or two neighbouring
+// characters swapped, e.g. `kubctl get pods` is one edit away from
+// `kubectl get pods`. How many edits are allowed grows with the length of
+// the query.
+//
+// Candidates are found with trigrams, every run of three characters of a
+// text. Every edit changes at most four of the trigrams of the query, so a
+// text within `k` edits shares all but `4 * k` of them. Candidates have to
+// share at least one, so that a short query isn't compared with every text,
+// which means a typo in every trigram of one, like `gti` for `git`, isn't
+// found.
+
+use std::collections::HashSet;
+
+/// What the edit distance costs in `TypoMatch::score`, compared to the
+/// trigram similarity, which is between 0 and this
+const SCORE_PER_EDIT: i64 = 1000;
+
+/// Where a query matched in some text, with typos
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypoMatch {
+    /// How many edits the query is away from the matched text
+    pub distance: usize,
+    /// Index of the first matched character
+    pub start: usize,
+    /// Index after the last matched character
+    pub end: usize,
+}
+
+impl TypoMatch {
+    /// Higher is better: fewer edits first, then more trigrams in common
+    /// with the whole text
+    pub fn score(&self, similarity: f64) -> i64 {
+        (similarity * SCORE_PER_EDIT as f64).round() as i64 - self.distance as i64 * SCORE_PER_EDIT
+    }
+}
+
+/// How good a match with `score` is, between 0 and 1, for a query that may
+/// be `typos` edits away from it: 1 for an exact match that shares every
+/// trigram with the text, and above 0 even for the worst one
+pub fn quality(score: i64, typos: usize) -> f64 {
+    let worst = (typos as i64 + 1) * SCORE_PER_EDIT;
+    (score + worst) as f64 / (worst + SCORE_PER_EDIT) as f64
+}
+
+/// The distinct runs of three characters in `text`, which should be case
+/// folded first
+pub fn trigrams(text: &str) -> HashSet<String> {
+    let chars: Vec<char> = text.chars().collect();
+    chars.windows(3).map(|w| w.iter().collect()).collect()
+}
+
+/// How many of its trigrams two texts have in common, out of all the
+/// trigrams either has, between 0 and 1
+pub fn similarity(a: &HashSet<String>, b: &HashSet<String>) -> f64 {
+    let shared = a.intersection(b).count();
+    match a.len() + b.len() - shared {
+        0 => 0.0,
+        all => shared as f64 / all as f64,
+    }
+}
+
+/// How many edits a query of `len` characters may be away from a match.
+/// Queries under three characters have to match exactly.
+pub fn max_typos(len: usize) -> usize {
+    match len {
+        0..=2 => 0,
+        _ => 1 + (len - 3) / 8,
+    }
+}
+
+/// How many trigrams of a query with `trigrams` of them a text has to
+/// share to be within `typos` edits of it, and at least one. `None` when the
+/// query has none.
+pub fn min_shared_trigrams(trigrams: usize, typos: usize) -> Option<usize> {
+    match trigrams {
+        0 => None,
+        _ => Some(trigrams.saturating_sub(4 * typos).max(1)),
+    }
+}
+
+/// The part of `text` the fewest edits away from `pattern`, if that's at
+/// most `max_distance`. Of equally close parts the first one wins.
+pub fn typo_match(pattern: &[char], text: &[char], max_distance: usize) -> Option<TypoMatch> {
+    let (m, n) = (pattern.len(), text.len());
+    // distance[i][j] is the fewest edits between the first i characters of
+    // the pattern and a part of the text ending at j, which starts at
+    // start[i][j]
+    let mut distance = vec![vec![0; n + 1]; m + 1];
+    let mut start = vec![vec![0; n + 1]; m + 1];
+    start[0] = (0..=n).collect();
+    for i in 1..=m {
+        distance[i][0] = i;
+        for j in 1..=n {
+            let cost = (pattern[i - 1] != text[j - 1]) as usize;
+            let mut best = (distance[i - 1][j - 1] + cost, start[i - 1][j - 1]);
+            for candidate in [
+                (distance[i - 1][j] + 1, start[i - 1][j]),
+                (distance[i][j - 1] + 1, start[i][j - 1]),
+            ] {
+                if candidate.0 < best.0 {
+                    best = candidate;
+                }
+            }
+            if i > 1
+                && j > 1
+                && pattern[i - 1] == text[j - 2]
+                && pattern[i - 2] == text[j - 1]
+                && distance[i - 2][j - 2] + 1 < best.0
+            {
+                best = (distance[i - 2][j - 2] + 1, start[i - 2][j - 2]);
+            }
+            distance[i][j] = best.0;
+            start[i][j] = best.1;
+        }
+    }
+
+    (0..=n)
+        .filter(|&j| distance[m][j] <= max_distance)
+        .min_by_key(|&j| distance[m][j])
+        .map(|j| TypoMatch {
+            distance: distance[m][j],
+            start: start[m][j],
+            end: j,
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn find(pattern: &str, text: &str, max_distance: usize) -> Option<TypoMatch> {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let text: Vec<char> = text.chars().collect();
+        typo_match(&pattern, &text, max_distance)
+    }
+
+    #[test]
+    fn test_typo_match() {
+        let m = find("kubctl get pods", "kubectl get pods -A", 3).unwrap();
+        assert_eq!((m.distance, m.start, m.end), (1, 0, 16));
+
+        // a swap is one edit
+        assert_eq!(find("gti", "git status", 1).unwrap().distance, 1);
+        assert_eq!(find("status", "git status", 0).unwrap().start, 4);
+        assert_eq!(find("statsu", "git status", 0), None);
+        assert_eq!(find("docker", "git status", 2), None);
+    }
+
+    #[test]
+    fn test_trigrams() {
+        let query = trigrams("kubctl");
+        let line = trigrams("kubectl");
+        assert_eq!(query.len(), 4);
+        assert_eq!(query.intersection(&line).count(), 2);
+        assert!((similarity(&query, &line) - 2.0 / 7.0).abs() < 1e-9);
+        assert!(trigrams("ls").is_empty());
+
+        assert_eq!(max_typos(2), 0);
+        assert_eq!(max_typos(6), 1);
+        assert_eq!(max_typos(15), 2);
+        assert_eq!(min_shared_trigrams(13, 2), Some(5));
+        assert_eq!(min_shared_trigrams(4, 1), Some(1));
+        assert_eq!(min_shared_trigrams(0, 0), None);
+    }
+}